@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use crate::error::Error;
+use crate::interpreter;
+use crate::vm::{VirtualMachine, Outcome, Rows};
+
+/// A handle to a single database file, used to embed Rustqlite
+/// without going through the REPL. Every statement is committed as soon
+/// as it succeeds unless a transaction was started with `BEGIN`, a
+/// transaction that is still open when the connection is dropped is
+/// rolled back
+///
+/// ```no_run
+/// use rustqlite::Connection;
+///
+/// let mut conn = Connection::open("data/app.rdb")?;
+/// conn.execute("CREATE TABLE users (name varchar, age int);")?;
+/// conn.execute("INSERT INTO users (name, age) VALUES ('Alice', 30);")?;
+///
+/// let rows = conn.query("SELECT name FROM users WHERE age > 18;")?;
+/// for row in &rows {
+///     println!("{:?}", row);
+/// }
+/// # Ok::<(), rustqlite::Error>(())
+/// ```
+pub struct Connection {
+    vm: VirtualMachine,
+}
+
+impl Connection {
+    /// Opens the database file at `path`, creating an empty one if it
+    /// does not exist yet. Opening `":memory:"` keeps the database in
+    /// memory, it is discarded once the connection is dropped
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Connection, Error>{
+        Ok(Connection{
+            vm: VirtualMachine::open(path.as_ref())?,
+        })
+    }
+
+    /// Runs the statements in `sql` in order and returns the number of rows
+    /// the last one changed. A SELECT can be run here too, in which case the
+    /// number of rows it found is returned. Nothing runs if any statement has
+    /// a syntax error, and the statements after one that fails are skipped
+    pub fn execute(&mut self, sql: &str) -> Result<usize, Error>{
+        match self.run(sql)? {
+            Outcome::Rows(rows) => Ok(rows.len()),
+            Outcome::Done{changes, ..} => Ok(changes),
+        }
+    }
+
+    /// Runs the statements in `sql` and returns the rows of the last one,
+    /// which has to be a SELECT
+    pub fn query(&mut self, sql: &str) -> Result<Rows, Error>{
+        match self.run(sql)? {
+            Outcome::Rows(rows) => Ok(rows),
+            Outcome::Done{..} => Err(Error::Execution(String::from("Statement does not return rows"))),
+        }
+    }
+
+    fn run(&mut self, sql: &str) -> Result<Outcome, Error>{
+        let mut outcome = Outcome::Done{
+            message: String::new(),
+            changes: 0,
+        };
+
+        for stmt in interpreter::interpret(sql)? {
+            outcome = self.vm.run(&stmt)?;
+        }
+        Ok(outcome)
+    }
+}
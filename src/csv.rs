@@ -0,0 +1,255 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use rustqlite::{Error, Literal};
+use rustqlite::interpreter::stmt::Stmt;
+use rustqlite::vm::VirtualMachine;
+use rustqlite::vm::table::Column;
+
+/// Name of the savepoint an import runs under, so a file that fails part
+/// way through leaves no rows behind while an open transaction stays open
+const IMPORT_SAVEPOINT: &str = "import";
+
+/// Reads comma separated records one at a time. Quoted fields may hold
+/// commas, doubled quotes and line breaks
+pub struct Reader<R> {
+    input: R,
+    line: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self{
+        Reader {
+            input,
+            line: 0,
+        }
+    }
+
+    /// Line the last record ended on, counted from one
+    pub fn line(&self) -> usize{
+        self.line
+    }
+
+    /// The next record, or None at the end of the input. Blank lines are skipped
+    pub fn record(&mut self) -> Result<Option<Vec<String>>, Error>{
+        let mut fields: Vec<String> = Vec::new();
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut text = String::new();
+
+        loop {
+            text.clear();
+            if self.input.read_line(&mut text)? == 0 {
+                if in_quotes {
+                    return Err(Error::Execution(format!("unterminated quoted field on line {}", self.line)));
+                }
+                if fields.is_empty() && field.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            self.line += 1;
+
+            if !in_quotes && fields.is_empty() && field.is_empty() && text.trim().is_empty() {
+                continue;
+            }
+
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes && chars.peek() == Some(&'"') => {
+                        field.push('"');
+                        chars.next();
+                    },
+                    '"' => in_quotes = !in_quotes,
+                    ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+                    '\r' | '\n' if !in_quotes => {},
+                    c => field.push(c),
+                }
+            }
+
+            if !in_quotes {
+                break;
+            }
+        }
+
+        fields.push(field);
+        Ok(Some(fields))
+    }
+}
+
+fn open(path: &str) -> Result<File, Error>{
+    File::open(path).map_err(|err| file_error(path, err))
+}
+
+/// Io errors are taken to be about the database file, so
+/// problems with the csv file are reported by name instead
+fn file_error(path: &str, err: io::Error) -> Error{
+    Error::Execution(format!("Unable to access {}: {}", path, err))
+}
+
+/// Fields holding a separator, quote or line break are quoted,
+/// with quotes inside them doubled
+pub fn field(field: &str) -> String{
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Loads the rows of a csv file into a table, the first record names the
+/// columns. A table that does not exist yet is created with the types that
+/// fit its columns, otherwise every value has to fit the table's schema.
+/// Returns the number of rows imported
+pub fn import(machine: &mut VirtualMachine, path: &str, table_name: &str) -> Result<usize, Error>{
+    machine.run(&Stmt::Savepoint{ name: IMPORT_SAVEPOINT.to_string() })?;
+
+    match load(machine, path, table_name) {
+        Ok(count) => {
+            machine.run(&Stmt::Release{ name: IMPORT_SAVEPOINT.to_string() })?;
+            Ok(count)
+        },
+        Err(err) => {
+            machine.run(&Stmt::RollbackTo{ name: IMPORT_SAVEPOINT.to_string() })?;
+            machine.run(&Stmt::Release{ name: IMPORT_SAVEPOINT.to_string() })?;
+            Err(err)
+        },
+    }
+}
+
+fn load(machine: &mut VirtualMachine, path: &str, table_name: &str) -> Result<usize, Error>{
+    let mut reader = Reader::new(BufReader::new(open(path)?));
+    let header: Vec<String> = match reader.record()? {
+        Some(header) => header.iter().map(|name| name.trim().to_string()).collect(),
+        None => return Err(Error::Execution(format!("{} is empty", path))),
+    };
+
+    if machine.table(table_name)?.is_none() {
+        let columns = header.iter().cloned()
+            .zip(infer_types(path, header.len())?)
+            .map(|(name, datatype)| Column::new(name, datatype))
+            .collect();
+        machine.run(&Stmt::Create{ table_name: table_name.to_string(), columns, unique: Vec::new(), sequence: None })?;
+    }
+
+    let table = match machine.table(table_name)? {
+        Some(table) => table,
+        None => return Err(Error::UnknownTable(table_name.to_string())),
+    };
+    let types: Vec<String> = header.iter()
+        .map(|name| table.schema.get(name).cloned().ok_or_else(|| Error::UnknownColumn(name.to_string())))
+        .collect::<Result<_, _>>()?;
+
+    let mut count = 0;
+    while let Some(record) = reader.record()? {
+        if record.len() != header.len() {
+            return Err(Error::Execution(format!("line {} has {} fields, expected {}",
+                                                reader.line(), record.len(), header.len())));
+        }
+
+        let target_values: Vec<Literal> = record.iter().zip(&header).zip(&types)
+            .map(|((value, column), datatype)| literal(value, column, datatype))
+            .collect::<Result<_, _>>()
+            .map_err(|err| Error::TypeMismatch(format!("{} on line {}", err, reader.line())))?;
+
+        machine.run(&Stmt::Insert{
+            table_name: table_name.to_string(),
+            target_columns: header.clone(),
+            target_values,
+        })
+        .map_err(|err| match err {
+            Error::ConstraintViolation(message) =>
+                Error::ConstraintViolation(format!("{} on line {}", message, reader.line())),
+            err => err,
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads the whole file once up front, rather than holding it in memory,
+/// to pick a type per column: int or bool when every value that is
+/// not empty fits it and varchar otherwise
+fn infer_types(path: &str, width: usize) -> Result<Vec<String>, Error>{
+    let mut reader = Reader::new(BufReader::new(open(path)?));
+    reader.record()?;
+
+    let mut is_int = vec![true; width];
+    let mut is_bool = vec![true; width];
+    let mut seen = vec![false; width];
+
+    while let Some(record) = reader.record()? {
+        for (idx, value) in record.iter().enumerate().take(width) {
+            if value.is_empty() {
+                continue;
+            }
+            seen[idx] = true;
+            is_int[idx] &= value.parse::<i64>().is_ok();
+            is_bool[idx] &= value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false");
+        }
+    }
+
+    Ok((0..width)
+        .map(|idx| {
+            let datatype = if !seen[idx] {
+                "varchar"
+            } else if is_int[idx] {
+                "int"
+            } else if is_bool[idx] {
+                "bool"
+            } else {
+                "varchar"
+            };
+            datatype.to_string()
+        })
+        .collect())
+}
+
+/// An empty field is NULL, anything else has to fit the column
+fn literal(value: &str, column: &str, datatype: &str) -> Result<Literal, String>{
+    if value.is_empty() {
+        return Ok(Literal::Null);
+    }
+
+    match datatype {
+        "int" => value.parse::<i64>()
+            .map(Literal::Number)
+            .map_err(|_| format!("'{}' is not an int for column '{}'", value, column)),
+        "bool" if value.eq_ignore_ascii_case("true") => Ok(Literal::Boolean(true)),
+        "bool" if value.eq_ignore_ascii_case("false") => Ok(Literal::Boolean(false)),
+        "bool" => Err(format!("'{}' is not a bool for column '{}'", value, column)),
+        _ => Ok(Literal::String(value.to_string())),
+    }
+}
+
+/// Writes every row of a table to a csv file, headed by the column names.
+/// Rows are written as they are read. Returns the number of rows exported
+pub fn export(machine: &mut VirtualMachine, table_name: &str, path: &str) -> Result<usize, Error>{
+    let table = match machine.table(table_name)? {
+        Some(table) => table,
+        None => return Err(Error::UnknownTable(table_name.to_string())),
+    };
+
+    let mut out = BufWriter::new(File::create(path).map_err(|err| file_error(path, err))?);
+    let header: Vec<String> = table.columns.iter().map(|col| field(&col.name)).collect();
+    writeln!(out, "{}", header.join(",")).map_err(|err| file_error(path, err))?;
+
+    let mut count = 0;
+    machine.scan_table(table_name, |values| {
+        let fields: Vec<String> = values.iter()
+            .map(|value| match value {
+                Literal::Number(val) => val.to_string(),
+                Literal::String(val) => field(val),
+                Literal::Boolean(val) => val.to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        writeln!(out, "{}", fields.join(",")).map_err(|err| file_error(path, err))?;
+        count += 1;
+        Ok(())
+    })?;
+
+    out.flush().map_err(|err| file_error(path, err))?;
+    Ok(count)
+}
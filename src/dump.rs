@@ -0,0 +1,262 @@
+use std::io::Write;
+
+use rustqlite::{Error, Literal};
+use rustqlite::vm::VirtualMachine;
+use rustqlite::vm::table::{Column, OnDelete, Table};
+
+/// Writes the SQL that rebuilds the tables of the main database, or only the
+/// one named, as a CREATE TABLE, an INSERT per row and a CREATE INDEX per
+/// index. The statements are wrapped in a transaction, which a replay rolls
+/// back at the first statement that fails, and rows are written as they are
+/// read. Tables come after the tables they refer to, references that cannot
+/// be met when a row is inserted are filled in by an UPDATE once every row is
+pub fn dump(machine: &mut VirtualMachine, table_name: Option<&str>, out: &mut impl Write) -> Result<(), Error>{
+    let tables: Vec<(String, Table, Vec<usize>)> = match table_name {
+        Some(name) => match machine.table(name)? {
+            Some(table) => {
+                let later = unmet(&table, &[]);
+                vec![(name.to_string(), table, later)]
+            },
+            None => return Err(Error::UnknownTable(name.to_string())),
+        },
+        None => parents_first(machine.tables()?.tables.into_iter().collect()),
+    };
+
+    writeln!(out, "BEGIN TRANSACTION;")?;
+
+    for (name, table, later) in &tables {
+        let mut definitions: Vec<String> = table.columns.iter().map(definition).collect();
+        for columns in &table.unique {
+            definitions.push(format!("UNIQUE ({})", columns.join(", ")));
+        }
+        // keys handed out and deleted since are not reused after replaying
+        let sequence = match table.sequence {
+            0 => String::new(),
+            sequence => format!(" AUTOINCREMENT = {}", sequence),
+        };
+        writeln!(out, "CREATE TABLE {} ({}){};", table.name, definitions.join(", "), sequence)?;
+
+        let columns: Vec<&str> = table.columns.iter().map(|col| col.get_name()).collect();
+        let columns = columns.join(", ");
+
+        machine.scan_table(name, |values| {
+            let values: Vec<String> = values.iter().enumerate()
+                .map(|(idx, value)| if later.contains(&idx) { Literal::Null.to_string() } else { value.to_string() })
+                .collect();
+            writeln!(out, "INSERT INTO {} ({}) VALUES ({});", table.name, columns, values.join(", "))?;
+            Ok(())
+        })?;
+
+        // indexes of UNIQUE constraints come back with the table
+        for index in table.indexes.iter().filter(|index| !index.auto) {
+            let unique = if index.unique { "UNIQUE " } else { "" };
+            writeln!(out, "CREATE {}INDEX {} ON {} ({});", unique, index.name, table.name, index.columns.join(", "))?;
+        }
+    }
+
+    // by now every row a reference left out can refer to has been inserted
+    for (name, table, later) in tables.iter().filter(|(_, _, later)| !later.is_empty()) {
+        let key = table.columns.iter().position(|col| col.primary_key).unwrap_or(0);
+
+        machine.scan_table(name, |values| {
+            let assignments: Vec<String> = later.iter()
+                .filter(|idx| values[**idx] != Literal::Null)
+                .map(|idx| format!("{} = {}", table.columns[*idx].name, values[*idx]))
+                .collect();
+            if !assignments.is_empty() {
+                writeln!(out, "UPDATE {} SET {} WHERE {} = {};",
+                         table.name, assignments.join(", "), table.columns[key].name, values[key])?;
+            }
+            Ok(())
+        })?;
+    }
+
+    writeln!(out, "COMMIT;")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// A column as it is written in CREATE TABLE, along with its constraints
+fn definition(column: &Column) -> String{
+    let mut definition = format!("{} {}", column.name, column.datatype);
+
+    if column.primary_key {
+        definition.push_str(" PRIMARY KEY");
+    }
+    if column.autoincrement {
+        definition.push_str(" AUTOINCREMENT");
+    }
+    if column.unique {
+        definition.push_str(" UNIQUE");
+    }
+    if column.not_null {
+        definition.push_str(" NOT NULL");
+    }
+    if let Some(default) = &column.default {
+        definition.push_str(&format!(" DEFAULT {}", default));
+    }
+    if let Some(check) = &column.check {
+        definition.push_str(&format!(" CHECK ({})", check));
+    }
+    if let Some(fk) = &column.references {
+        definition.push_str(&format!(" REFERENCES {}({})", fk.table, fk.column));
+        match fk.on_delete {
+            OnDelete::Restrict => {},
+            OnDelete::Cascade => definition.push_str(" ON DELETE CASCADE"),
+            OnDelete::SetNull => definition.push_str(" ON DELETE SET NULL"),
+        }
+    }
+    definition
+}
+
+/// Orders tables so rows are inserted after the rows they refer to in other
+/// tables. When every table left refers to another one left, as tables
+/// referring to each other do, the first of them is taken anyway. Each table
+/// comes with the columns its rows are inserted without
+fn parents_first(mut pending: Vec<(String, Table)>) -> Vec<(String, Table, Vec<usize>)>{
+    let mut ordered = Vec::new();
+
+    while !pending.is_empty() {
+        let ready = pending.iter()
+            .position(|(_, table)| table.columns.iter().all(|col| match &col.references {
+                Some(fk) => fk.table == table.name ||
+                    !pending.iter().any(|(name, _)| *name == fk.table),
+                None => true,
+            }))
+            .unwrap_or(0);
+        let (name, table) = pending.remove(ready);
+        let later = unmet(&table, &pending);
+        ordered.push((name, table, later));
+    }
+    ordered
+}
+
+/// Positions of the columns referring to rows that may not have been inserted
+/// yet, those of the table itself, which can refer to rows after their own,
+/// and those of the tables still to be written
+fn unmet(table: &Table, pending: &[(String, Table)]) -> Vec<usize>{
+    table.columns.iter().enumerate()
+        .filter(|(_, col)| match &col.references {
+            Some(fk) => fk.table == table.name || pending.iter().any(|(name, _)| *name == fk.table),
+            None => false,
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use rustqlite::{interpreter, vm, Error, Literal};
+    use rustqlite::vm::{Outcome, VirtualMachine};
+
+    use super::dump;
+    use crate::render::Output;
+
+    fn memory() -> Result<VirtualMachine, Error>{
+        VirtualMachine::open(Path::new(vm::MEMORY_PATH))
+    }
+
+    fn execute(machine: &mut VirtualMachine, sql: &str) -> Result<Vec<Vec<Literal>>, Error>{
+        let mut rows = Vec::new();
+        for stmt in interpreter::interpret(sql)? {
+            if let Outcome::Rows(result) = machine.run(&stmt)? {
+                rows = result.rows;
+            }
+        }
+        Ok(rows)
+    }
+
+    fn dumped(machine: &mut VirtualMachine) -> Result<String, Error>{
+        let mut out = Vec::new();
+        dump(machine, None, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn replaying_a_dump_rebuilds_the_database() -> Result<(), Error>{
+        let mut machine = memory()?;
+        execute(&mut machine, "CREATE TABLE books (id int PRIMARY KEY AUTOINCREMENT, title varchar NOT NULL, author int REFERENCES authors(id));
+            CREATE TABLE authors (id int PRIMARY KEY, name varchar UNIQUE, born int CHECK (born > 0));
+            CREATE INDEX bytitle ON books (title);
+            INSERT INTO authors (id, name, born) VALUES (1, 'O''Brien', 1911);
+            INSERT INTO authors (id, name, born) VALUES (2, NULL, NULL);
+            INSERT INTO books (title, author) VALUES ('first', 1);
+            INSERT INTO books (title, author) VALUES ('second', 2);
+            INSERT INTO books (title) VALUES ('third');
+            DELETE FROM books WHERE id = 3;")?;
+        let sql = dumped(&mut machine)?;
+
+        let mut restored = memory()?;
+        execute(&mut restored, &sql)?;
+        assert_eq!(dumped(&mut restored)?, sql);
+
+        // deleted keys stay used and the index is back
+        execute(&mut restored, "INSERT INTO books (title) VALUES ('fourth');")?;
+        assert_eq!(execute(&mut restored, "SELECT id FROM books WHERE title = 'fourth';")?, [[Literal::Number(4)]]);
+        let plan = execute(&mut restored, "EXPLAIN QUERY PLAN SELECT id FROM books WHERE title = 'first';")?;
+        assert!(plan.iter().any(|row| matches!(&row[2], Literal::String(detail) if detail.contains("bytitle"))));
+        Ok(())
+    }
+
+    #[test]
+    fn a_failing_replay_leaves_nothing_behind() -> Result<(), Error>{
+        let mut machine = memory()?;
+        execute(&mut machine, "CREATE TABLE shop (item varchar NOT NULL);
+            INSERT INTO shop (item) VALUES ('pen');
+            INSERT INTO shop (item) VALUES ('ink');")?;
+        // the second row breaks the constraint once the dump is edited
+        let sql = dumped(&mut machine)?.replace("'ink'", "NULL");
+
+        let path = std::env::temp_dir().join(format!("rustqlite-dump-{}.sql", std::process::id()));
+        fs::write(&path, sql).unwrap();
+
+        let mut restored = memory()?;
+        let read = format!(".read {}", path.display());
+        let succeeded = crate::run_command(&mut restored, &mut Output::default(), &read);
+        fs::remove_file(&path).unwrap();
+
+        assert!(!succeeded);
+        assert!(!restored.in_transaction());
+        assert!(restored.table("shop")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn rows_referring_to_later_rows_of_their_own_table_are_restored() -> Result<(), Error>{
+        let mut machine = memory()?;
+        execute(&mut machine, "CREATE TABLE e (id int PRIMARY KEY, boss int REFERENCES e(id));
+            INSERT INTO e (id, boss) VALUES (5, NULL);
+            INSERT INTO e (id, boss) VALUES (2, 5);
+            INSERT INTO e (id, boss) VALUES (8, 2);")?;
+        let sql = dumped(&mut machine)?;
+        assert!(sql.contains("INSERT INTO e (id, boss) VALUES (2, NULL);\nINSERT INTO e (id, boss) VALUES (5, NULL);"));
+
+        let mut restored = memory()?;
+        execute(&mut restored, &sql)?;
+        let query = "SELECT id, boss FROM e;";
+        assert_eq!(execute(&mut restored, query)?, execute(&mut machine, query)?);
+        assert_eq!(dumped(&mut restored)?, sql);
+        Ok(())
+    }
+
+    #[test]
+    fn tables_referring_to_each_other_are_restored() -> Result<(), Error>{
+        let mut machine = memory()?;
+        execute(&mut machine, "CREATE TABLE a (id int PRIMARY KEY, partner int REFERENCES b(id));
+            CREATE TABLE b (id int PRIMARY KEY, partner int REFERENCES a(id));
+            INSERT INTO a (id) VALUES (1);
+            INSERT INTO b (id, partner) VALUES (1, 1);
+            UPDATE a SET partner = 1 WHERE id = 1;")?;
+        let sql = dumped(&mut machine)?;
+
+        let mut restored = memory()?;
+        execute(&mut restored, &sql)?;
+        for query in ["SELECT id, partner FROM a;", "SELECT id, partner FROM b;"] {
+            assert_eq!(execute(&mut restored, query)?, execute(&mut machine, query)?);
+        }
+        Ok(())
+    }
+}
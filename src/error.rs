@@ -0,0 +1,60 @@
+use std::fmt;
+use std::io;
+
+/// Every failure the scanner, parser or virtual machine can report.
+/// Callers can match on the kind of error, while the REPL decides
+/// how each one is presented
+#[derive(Debug)]
+pub enum Error {
+    /// The SQL could not be scanned or parsed. The position is the
+    /// character offset into the input where the problem was found
+    Syntax{
+        message: String,
+        position: usize,
+    },
+    /// The statement names a table that does not exist
+    UnknownTable(String),
+    /// The statement names a column the table does not have
+    UnknownColumn(String),
+    /// A value does not fit the datatype of its column, or two
+    /// values of different types were compared
+    TypeMismatch(String),
+    /// The statement would break a rule of the table definition
+    ConstraintViolation(String),
+    /// Reading or writing the database file failed
+    Io(io::Error),
+    /// The database file exists but its contents could not be decoded
+    Corruption(String),
+    /// Any other reason a valid statement could not be carried out
+    Execution(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            Error::Syntax{message, position} => write!(f, "{} (at position {})", message, position),
+            Error::UnknownTable(name) => write!(f, "Target table '{}' not found", name),
+            Error::UnknownColumn(name) => write!(f, "Unknown column '{}'", name),
+            Error::TypeMismatch(msg) => write!(f, "{}", msg),
+            Error::ConstraintViolation(msg) => write!(f, "{}", msg),
+            Error::Io(err) => write!(f, "Unable to access database file: {}", err),
+            Error::Corruption(msg) => write!(f, "Database file is corrupted: {}", msg),
+            Error::Execution(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self{
+        Error::Io(err)
+    }
+}
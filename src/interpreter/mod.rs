@@ -3,25 +3,71 @@ mod parser;
 pub mod token;
 pub mod stmt;
 
-use colored::*;
-use crate::interpreter::stmt::{Stmt};
+use crate::error::Error;
+use crate::interpreter::stmt::{Stmt, Expr};
 use scanner::Scanner;
 use parser::Parser;
 
-pub fn interpret(cmd: &str) -> Result<Stmt, ColoredString> {
+/// Turns SQL into the statements it holds, in order. Nothing is returned
+/// unless every statement is valid, a syntax error reports its position
+/// in `cmd` counted in characters
+pub fn interpret(cmd: &str) -> Result<Vec<Stmt>, Error> {
     let mut scanner: Scanner = Scanner::new(cmd);
-    let tokens = match scanner.scan(){
-        Ok(tks) => tks,
-        Err(err) => return Err(err.red()),
-    };
+    let tokens = scanner.scan()?;
 
     let mut parser: Parser = Parser::new(&tokens);
-    let statement = parser.parse();
-   
-    match statement {
-        Ok(stmt) => Ok(stmt),
-        Err(err) => {
-           Err(err.red())
-        },
+    parser.parse()
+}
+
+/// Turns an expression such as `age > 18 AND NOT active`, written as it
+/// would be after WHERE, into its tree
+pub fn interpret_expression(expr: &str) -> Result<Expr, Error> {
+    let mut scanner: Scanner = Scanner::new(expr);
+    let tokens = scanner.scan()?;
+
+    let mut parser: Parser = Parser::new(&tokens);
+    parser.expression()
+}
+
+/// Splits input into the part made up of complete statements, up to and
+/// including the last `;`, and whatever follows it. A `;` inside a quoted
+/// string or a `--` comment does not end a statement, and a tail of nothing
+/// but whitespace and comments counts as empty
+pub fn split_complete(input: &str) -> (&str, &str){
+    let mut end = 0;
+    let mut chars = input.char_indices().peekable();
+    let mut in_string = false;
+    let mut is_blank = true;
+
+    while let Some((idx, c)) = chars.next() {
+        if in_string {
+            // a doubled quote is an escaped quote, which toggles
+            // in and out of the string and so needs no special case
+            in_string = c != '\'';
+            continue;
+        }
+
+        match c {
+            '\'' => {
+                in_string = true;
+                is_blank = false;
+            },
+            '-' if matches!(chars.peek(), Some((_, '-'))) => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            },
+            ';' => {
+                end = idx + 1;
+                is_blank = true;
+            },
+            c if c.is_whitespace() => {},
+            _ => is_blank = false,
+        }
     }
+
+    let rest = if is_blank { "" } else { &input[end..] };
+    (&input[..end], rest)
 }
@@ -1,5 +1,7 @@
 use crate::interpreter::token::{TokenType, Token, Literal};
-use crate::interpreter::stmt::{Stmt};
+use crate::interpreter::stmt::{Stmt, Expr};
+use crate::vm::table::{Column, ForeignKey, OnDelete};
+use crate::error::Error;
 use std::cell::Cell;
 
 #[derive(Debug)]
@@ -11,7 +13,7 @@ pub struct Parser<'p>{
 impl<'p> Parser<'p>{
   pub fn new(tokens: &'p [Token]) -> Self{
         Parser{
-            tokens: tokens,
+            tokens,
             position: Cell::new(0),
         }
    }
@@ -31,8 +33,48 @@ impl<'p> Parser<'p>{
         self.tokens.get(self.position.get() + 1)
   }
 
-  //we can expect to return one type of statement in this project
- pub fn parse(&mut self) -> Result<Stmt, &str>{
+  /// Syntax errors point at the token the parser stopped on, or just
+  /// past the last token when the input ran out early
+  fn error(&self, message: &str) -> Error{
+    let position = match self.peek() {
+        Some(token) => token.position,
+        None => match self.tokens.last() {
+            Some(token) => token.position + token.lexeme.chars().count(),
+            None => 0,
+        },
+    };
+
+    Error::Syntax{
+        message: String::from(message),
+        position,
+    }
+  }
+
+  /// Parses every statement in the input, in order. Each statement
+  /// parser stops on the `;` that ends it, so the next one starts
+  /// right after it. Empty statements are skipped
+  pub fn parse(&mut self) -> Result<Vec<Stmt>, Error>{
+    let mut statements = Vec::new();
+
+    while let Some(token) = self.peek() {
+        if !matches!(token.token_type, TokenType::SemiColon) {
+            statements.push(self.statement()?);
+        }
+        self.advance();
+    }
+    Ok(statements)
+  }
+
+  /// Parses input that holds a single expression and nothing after it
+  pub fn expression(&mut self) -> Result<Expr, Error>{
+    let expr = self.parse_expression()?;
+    match self.peek() {
+        Some(_) => Err(self.error("invalid syntax, expected end of expression")),
+        None => Ok(expr),
+    }
+  }
+
+ fn statement(&mut self) -> Result<Stmt, Error>{
         let stmt_token = self.peek();
         
         match stmt_token {
@@ -44,14 +86,19 @@ impl<'p> Parser<'p>{
                     TokenType::Delete => self.delete_stmt(),
                     TokenType::Insert => self.insert_stmt(),
                     TokenType::Update => self.update_stmt(),
-                    _ => Err("invalid syntax, invalid start to SQL statement")
+                    TokenType::Attach => self.attach_stmt(),
+                    TokenType::Detach => self.detach_stmt(),
+                    TokenType::Begin | TokenType::Commit | TokenType::Rollback => self.transaction_stmt(),
+                    TokenType::Savepoint | TokenType::Release => self.savepoint_stmt(),
+                    TokenType::Explain => self.explain_stmt(),
+                    _ => Err(self.error("invalid syntax, invalid start to SQL statement"))
                 }
             },
-            None => Err("invalid syntax, invalid start to SQL statement") 
+            None => Err(self.error("invalid syntax, invalid start to SQL statement")) 
         }
     }
     
-  fn select_stmt(&self) -> Result<Stmt, &str>{
+  fn select_stmt(&self) -> Result<Stmt, Error>{
      let mut columns_set: Vec<String> = Vec::new();
      self.advance();
      let first_col = self.peek(); 
@@ -65,13 +112,13 @@ impl<'p> Parser<'p>{
                },
                TokenType::Identifier => { 
                    if self.parse_comma_list(&mut columns_set).is_err() {
-                        return Err("Invalid syntax, check selected columns");
+                        return Err(self.error("Invalid syntax, check selected columns"));
                     } 
                },
-               _ =>  return Err("Invalid syntax, expected 'all' type or column name identifier"),
+               _ =>  return Err(self.error("Invalid syntax, expected 'all' type or column name identifier")),
              }
         },
-        None => return Err("Invalid syntax, check statement"),
+        None => return Err(self.error("Invalid syntax, check statement")),
      }
      
      let from_token = self.peek();
@@ -80,104 +127,93 @@ impl<'p> Parser<'p>{
         Some(token) => {
              match token.token_type {
                 TokenType::From => self.advance(),
-                _ => return Err("invalid syntax, expected 'from'"),
+                _ => return Err(self.error("invalid syntax, expected 'from'")),
              };
         },
-        None => return Err("Invalid syntax, check statement"),
+        None => return Err(self.error("Invalid syntax, check statement")),
      }
     
-    let table_name: &str = self.get_table_name()?;
-    
-    let mut has_conditions = false;
+    let table_name = self.get_table_name()?;
+    self.advance();
 
-    if let Some(token) = self.peek_next(){
-       match token.token_type {
-            TokenType::Where => {
-                self.advance();
-                self.advance();
-                has_conditions = true;
-            },
-            _ => {},
-       }
+    let mut where_clause = None;
+    if let Some(Token{token_type: TokenType::Where, ..}) = self.peek(){
+        self.advance();
+        where_clause = Some(self.parse_expression()?);
     }
 
-    if has_conditions {
-        let (target_cols, has_vals) = self.parse_equality_list()?;
-
-        match self.expect_terminator() {
-            Ok(_end) => {
-                 return Ok(Stmt::Select{
-                            table_name: String::from(table_name), 
-                            target_columns: columns_set,
-                            where_conditions: Some((target_cols, has_vals)),
-                            });
-            
-            },
-            Err(err) => return Err(err),
-        }
+    let mut order_by = Vec::new();
+    if let Some(Token{token_type: TokenType::Order, ..}) = self.peek(){
+        self.advance();
+        order_by = self.parse_order_by()?;
     }
-    match self.expect_terminator() {
-        Ok(_end) =>  Ok(Stmt::Select{
-                        table_name: String::from(table_name), 
-                        target_columns: columns_set,
-                        where_conditions: None,
-                        }), 
-        Err(err) => Err(err),
+
+    let mut limit = None;
+    if let Some(Token{token_type: TokenType::Limit, ..}) = self.peek(){
+        self.advance();
+        limit = match self.peek() {
+            Some(Token{literal: Some(Literal::Number(count)), ..}) if *count >= 0 => Some(*count as usize),
+            _ => return Err(self.error("Invalid syntax, expected a row count after 'limit'")),
+        };
+        self.advance();
     }
+
+    self.expect_end()?;
+    Ok(Stmt::Select{
+        table_name, 
+        target_columns: columns_set,
+        where_clause,
+        order_by,
+        limit,
+    })
   }
 
-  fn drop_stmt(&self) -> Result<Stmt, &str>{
+  fn drop_stmt(&self) -> Result<Stmt, Error>{
       self.advance();
               
-      if let Some(token) = self.peek(){
-          match token.token_type {
-            TokenType::Table => self.advance(),
-            _ => return Err("Invalid syntax, expected keyword 'table'"),
-          }
-      }
+      let is_index = match self.peek().map(|token| &token.token_type) {
+          Some(TokenType::Table) => false,
+          Some(TokenType::Index) => true,
+          _ => return Err(self.error("Invalid syntax, expected keyword 'table' or 'index'")),
+      };
+      self.advance();
 
     let table_name = self.get_table_name()?;
  
-    match self.expect_terminator() {
-        Ok(_end) => Ok(Stmt::Drop{table_name: String::from(table_name)}),
-        Err(err) => Err(err),
+    self.expect_terminator()?;
+    if is_index {
+        return Ok(Stmt::DropIndex{index_name: table_name});
     }
+    Ok(Stmt::Drop{table_name})
   }
 
-  fn insert_stmt(&self) -> Result<Stmt, &str>{
+  fn insert_stmt(&self) -> Result<Stmt, Error>{
     self.advance(); 
     match self.peek() {
         Some(action) => {
             match action.token_type {
                 TokenType::Into => self.advance(),
-                _ => return Err("invalid syntax, expect 'into'"),
+                _ => return Err(self.error("invalid syntax, expect 'into'")),
             };
         },
-        None => return Err("Invalid syntax, check statement"),
+        None => return Err(self.error("Invalid syntax, check statement")),
     }
 
     let table_name = self.get_table_name()?; 
     
     self.advance();
 
-    let mut is_values = false;
     let mut col_list = Vec::new();
     let mut val_list = Vec::new();
 
     //Get collected set of columns and/or values
 
-    if let Some(next) = self.peek(){
-       match next.token_type {
-            TokenType::Values => is_values = true,
-            _ => {},
-       }
-    }
-
-    if is_values {
+    if let Some(Token{token_type: TokenType::Values, ..}) = self.peek(){
        self.advance();
        self.parse_comma_list_literal(&mut val_list)?;
+       self.expect_terminator()?;
        return Ok(Stmt::Insert{
-            table_name: table_name.to_string(), 
+            table_name, 
             target_columns: col_list, 
             target_values: val_list
         });
@@ -186,7 +222,7 @@ impl<'p> Parser<'p>{
     if let Some(next) = self.peek(){
         match next.token_type {
             TokenType::LeftParen => self.parse_comma_list(&mut col_list)?,  
-            _ => return Err("Invalid syntax, expected target column list"),
+            _ => return Err(self.error("Invalid syntax, expected target column list")),
         }
     }
 
@@ -195,7 +231,7 @@ impl<'p> Parser<'p>{
    if let Some(vals) = self.peek(){
         match vals.token_type {
             TokenType::Values => self.advance(),
-            _ => return Err("invalid syntax, expected 'values' keyword"),
+            _ => return Err(self.error("invalid syntax, expected 'values' keyword")),
         }
    }
 
@@ -203,23 +239,20 @@ impl<'p> Parser<'p>{
     if let Some(next) = self.peek(){
         match next.token_type {
             TokenType::LeftParen => self.parse_comma_list_literal(&mut val_list)?, 
-            _ => return Err("invalid syntax, expected value list"),
+            _ => return Err(self.error("invalid syntax, expected value list")),
         }
     }
 
-    match self.expect_terminator() {
-        Ok(true) => Ok(Stmt::Insert{
-            table_name: table_name.to_string(), 
-            target_columns: col_list, 
-            target_values: val_list
-        }),
-        Ok(false) => Err("invalid end to statement"),
-        Err(err) => Err(err),
-    }
+    self.expect_terminator()?;
+    Ok(Stmt::Insert{
+        table_name, 
+        target_columns: col_list, 
+        target_values: val_list
+    })
   }
 
 
-  fn update_stmt(&self) -> Result<Stmt, &str>{
+  fn update_stmt(&self) -> Result<Stmt, Error>{
     self.advance();
     let table_name = self.get_table_name()?;
     
@@ -229,10 +262,10 @@ impl<'p> Parser<'p>{
         Some(set) => {
             match set.token_type{
                TokenType::Set => self.advance(),
-               _ => return Err("Invalid syntax, expected 'set'"),
+               _ => return Err(self.error("Invalid syntax, expected 'set'")),
             };
         },
-        None => return Err("Invalid syntax, check statement"),
+        None => return Err(self.error("Invalid syntax, check statement")),
     }
 
     let (target_cols, new_vals) = self.parse_equality_list()?;
@@ -241,36 +274,31 @@ impl<'p> Parser<'p>{
         Some(token) => {
             match token.token_type {
                 TokenType::Where => self.advance(),
-                _ => return Err("Invalid syntax, expected 'where'"),
+                _ => return Err(self.error("Invalid syntax, expected 'where'")),
             };
         },
-        None => return Err("Invalid syntax, check statement"),
+        None => return Err(self.error("Invalid syntax, check statement")),
     }
 
-    let (lhs, rhs) = self.parse_equality_list()?;
+    let where_clause = self.parse_expression()?;
+    self.expect_end()?;
 
-    match self.expect_terminator() {
-        Ok(true) => {
-            Ok(Stmt::Update{
-                        table_name: table_name.to_string(), 
-                        where_col: lhs, 
-                        where_val: rhs, 
-                        target_columns: target_cols, 
-                        target_values: new_vals
-                    })
-        },
-        Ok(false) => return Err("expected end to statement"),
-        Err(err) => Err(err),
-    }
+    Ok(Stmt::Update{
+        table_name,
+        where_clause,
+        target_columns: target_cols,
+        target_values: new_vals
+    })
   }
 
-  fn create_stmt(&mut self) -> Result<Stmt, &str>{
+  fn create_stmt(&mut self) -> Result<Stmt, Error>{
     self.advance();
 
     if let Some(table_token) = self.peek(){
         match table_token.token_type {
             TokenType::Table => self.advance(),
-            _ => return Err("Invalid syntax, incomplete Create Table statement"),
+            TokenType::Index | TokenType::Unique => return self.create_index_stmt(),
+            _ => return Err(self.error("Invalid syntax, incomplete Create Table statement")),
         }
     }
 
@@ -284,38 +312,73 @@ impl<'p> Parser<'p>{
                 TokenType::LeftParen => {
                     self.advance();
                     
-                    let def = self.parse_create_list()?;
-                    
-                    match self.expect_terminator() {
-                        Ok(true) => {
-                            return Ok(Stmt::Create{
-                                      table_name: table_name.to_string(), 
-                                      columns_and_data: def
-                                  }); 
-                        }, 
-                        Ok(false) => return Err("expected end to statement"),
-                        Err(err) => return Err(err), 
-                    }
+                    let (columns, unique) = self.parse_create_list()?;
+                    self.advance();
+                    let sequence = self.parse_sequence()?;
+
+                    self.expect_end()?;
+                    Ok(Stmt::Create{
+                        table_name, 
+                        columns,
+                        unique,
+                        sequence,
+                    })
                 },
-                _ => Err("Invalid syntax, expected parenthesis-bound list"),
+                _ => Err(self.error("Invalid syntax, expected parenthesis-bound list")),
                 }
             },
-            None => return Err("invalid syntax, missing token for valid statement"),
+            None => Err(self.error("invalid syntax, missing token for valid statement")),
         } 
     }
     
 
-  fn delete_stmt(&mut self) -> Result<Stmt, &str>{
+  /// create_index -> "create" "unique"? "index" identifier "on" table "(" identifier ( "," identifier )* ")"
+  fn create_index_stmt(&self) -> Result<Stmt, Error>{
+    let unique = matches!(self.peek(), Some(Token{token_type: TokenType::Unique, ..}));
+    if unique {
+        self.advance();
+    }
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::Index, ..}) => self.advance(),
+        _ => return Err(self.error("Invalid syntax, expected keyword 'index'")),
+    }
+
+    let index_name = match self.peek() {
+        Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => lexeme.clone(),
+        _ => return Err(self.error("Invalid syntax, expected index name")),
+    };
+    self.advance();
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::On, ..}) => self.advance(),
+        _ => return Err(self.error("Invalid syntax, expected keyword 'on'")),
+    }
+
+    let table_name = self.get_table_name()?;
+    self.advance();
+    let columns = self.parse_column_names()?;
+
+    self.expect_end()?;
+    Ok(Stmt::CreateIndex{
+        index_name,
+        table_name,
+        columns,
+        unique,
+    })
+  }
+
+  fn delete_stmt(&mut self) -> Result<Stmt, Error>{
     self.advance();
     match self.peek(){
         Some(token) => {
             match token.token_type{
                 TokenType::From => self.advance(),
-                _ => return Err("invalid syntax, expected 'from'"),
+                _ => return Err(self.error("invalid syntax, expected 'from'")),
             }
         },
-        None => return Err("invalid syntax, check statement"),
-    }
+        None => return Err(self.error("invalid syntax, check statement")),
+    };
    
     let name = self.get_table_name()?;
     
@@ -325,52 +388,196 @@ impl<'p> Parser<'p>{
         Some(token) => {
             match token.token_type{ 
                 TokenType::Where => {}, 
-                _ => return Err("invalid syntax, expected 'where'"),
+                _ => return Err(self.error("invalid syntax, expected 'where'")),
             }
         },
-        None => return Err("invalid syntax, check statement"),
+        None => return Err(self.error("invalid syntax, check statement")),
     }
     self.advance();
 
-    let (lhs, rhs) = self.parse_equality_list()?;
-         
+    let where_clause = self.parse_expression()?;
+    self.expect_end()?;
+
     Ok(Stmt::Delete{
-        table_name: name.to_string(), 
-        lhs, 
-        rhs,
-    })   
+        table_name: name,
+        where_clause,
+    })
+  }
+
+  /// Table names may be qualified with the name of an attached
+  /// database, `other.table`, which is kept joined by a dot
+  fn attach_stmt(&self) -> Result<Stmt, Error>{
+    self.advance();
+
+    if let Some(Token{token_type: TokenType::Database, ..}) = self.peek(){
+        self.advance();
+    }
+
+    let path = match self.peek() {
+        Some(Token{token_type: TokenType::String, lexeme, ..}) => lexeme.clone(),
+        _ => return Err(self.error("invalid syntax, expected quoted database path")),
+    };
+    self.advance();
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::As, ..}) => self.advance(),
+        _ => return Err(self.error("invalid syntax, expected 'as'")),
+    }
+
+    let schema_name = match self.peek() {
+        Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => lexeme.clone(),
+        _ => return Err(self.error("invalid syntax, expected database name")),
+    };
+
+    self.expect_terminator()?;
+    Ok(Stmt::Attach{path, schema_name})
+  }
+
+  fn detach_stmt(&self) -> Result<Stmt, Error>{
+    self.advance();
+
+    if let Some(Token{token_type: TokenType::Database, ..}) = self.peek(){
+        self.advance();
+    }
+
+    let schema_name = match self.peek() {
+        Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => lexeme.clone(),
+        _ => return Err(self.error("invalid syntax, expected database name")),
+    };
+
+    self.expect_terminator()?;
+    Ok(Stmt::Detach{schema_name})
+  }
+
+  /// BEGIN, COMMIT and ROLLBACK, each optionally followed by TRANSACTION.
+  /// ROLLBACK may also name a savepoint: `ROLLBACK TO [SAVEPOINT] name;`
+  fn transaction_stmt(&self) -> Result<Stmt, Error>{
+    let stmt = match self.peek() {
+        Some(Token{token_type: TokenType::Begin, ..}) => Stmt::Begin,
+        Some(Token{token_type: TokenType::Commit, ..}) => Stmt::Commit,
+        _ => Stmt::Rollback,
+    };
+
+    if let Some(Token{token_type: TokenType::Transaction, ..}) = self.peek_next(){
+        self.advance();
+    }
+
+    if let (Stmt::Rollback, Some(Token{token_type: TokenType::To, ..})) = (&stmt, self.peek_next()){
+        self.advance();
+        let name = self.savepoint_name()?;
+        self.expect_terminator()?;
+        return Ok(Stmt::RollbackTo{name});
+    }
+
+    self.expect_terminator()?;
+    Ok(stmt)
+  }
+
+  /// `SAVEPOINT name;` and `RELEASE [SAVEPOINT] name;`
+  fn savepoint_stmt(&self) -> Result<Stmt, Error>{
+    let is_release = matches!(self.peek(), Some(Token{token_type: TokenType::Release, ..}));
+    let name = self.savepoint_name()?;
+    self.expect_terminator()?;
+
+    if is_release {
+        Ok(Stmt::Release{name})
+    } else {
+        Ok(Stmt::Savepoint{name})
+    }
+  }
+
+  /// Moves on to the name of a savepoint, skipping the optional SAVEPOINT
+  /// keyword that RELEASE and ROLLBACK TO allow in front of it
+  fn savepoint_name(&self) -> Result<String, Error>{
+    self.advance();
+
+    if let Some(Token{token_type: TokenType::Savepoint, ..}) = self.peek(){
+        self.advance();
+    }
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => Ok(lexeme.clone()),
+        _ => Err(self.error("invalid syntax, expected savepoint name")),
+    }
+  }
+
+  /// explain -> "explain" ( "query" "plan" )? statement
+  fn explain_stmt(&mut self) -> Result<Stmt, Error>{
+    self.advance();
+
+    let query_plan = matches!(self.peek(), Some(Token{token_type: TokenType::Query, ..}));
+    if query_plan {
+        self.advance();
+        match self.peek() {
+            Some(Token{token_type: TokenType::Plan, ..}) => self.advance(),
+            _ => return Err(self.error("Invalid syntax, expected keyword 'plan' after 'query'")),
+        }
+    }
+
+    if let Some(Token{token_type: TokenType::Explain, ..}) = self.peek() {
+        return Err(self.error("Invalid syntax, cannot explain an explain statement"));
+    }
+
+    let statement = self.statement()?;
+    Ok(Stmt::Explain{
+        query_plan,
+        statement: Box::new(statement),
+    })
   }
 
-  fn get_table_name(&self)  -> Result<&str, &str>{
+  fn get_table_name(&self)  -> Result<String, Error>{
     let table_token = self.peek();
-    match table_token {
+    let name = match table_token {
         Some(token) => {
             match token.token_type {
-                TokenType::Identifier => Ok(&token.lexeme),
-                _ =>  Err("Invalid syntax, expected identifier")
+                TokenType::Identifier => token.lexeme.clone(),
+                _ =>  return Err(self.error("Invalid syntax, expected identifier"))
             }
         },
-        None => return Err("Invalid syntax, check statement format"),
+        None => return Err(self.error("Invalid syntax, check statement format")),
+    };
+
+    if let Some(Token{token_type: TokenType::Dot, ..}) = self.peek_next(){
+        self.advance();
+        self.advance();
+        return match self.peek() {
+            Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => Ok(format!("{}.{}", name, lexeme)),
+            _ => Err(self.error("Invalid syntax, expected table name after '.'")),
+        };
     }
+    Ok(name)
   }
 
 
-  fn expect_terminator(&self) -> Result<bool, &str> {
-    let terminator_token = self.peek_next();
-    match terminator_token {
-        Some(token) => {
-            match token.token_type{
-                TokenType::SemiColon => Ok(true),
-                TokenType::Where => Ok(false),
-                _ => Err("Invalid syntax, expected line terminator")
-            } 
-        },
-        None => return Err("Invalid syntax, expected terminator token"),
+  /// Most statements end on their last token, so the terminator is
+  /// expected to follow it. Either way the parser moves on to the
+  /// next token, leaving it on the `;` or on whatever took its place
+  fn expect_terminator(&self) -> Result<(), Error> {
+    self.advance();
+    self.expect_end()
+  }
+
+  /// Expressions consume all of their tokens, so the terminator is
+  /// expected at the current position rather than the next one
+  fn expect_end(&self) -> Result<(), Error> {
+    match self.peek() {
+        Some(Token{token_type: TokenType::SemiColon, ..}) => Ok(()),
+        Some(_) => Err(self.error("Invalid syntax, expected line terminator")),
+        None => Err(self.error("Invalid syntax, expected terminator token")),
     }
   }
 
-  fn parse_create_list(&self) -> Result<Vec<(String, String)>, &str> {
+  /// Parses the column definitions and table constraints of a create table statement:
+  ///
+  ///   item       -> column | "unique" "(" identifier ( "," identifier )* ")"
+  ///   column     -> identifier type constraint*
+  ///   constraint -> "not" "null" | "null" | "default" literal | "check" "(" expression ")"
+  ///               | "primary" "key" "autoincrement"? | "unique" | references
+  ///   references -> "references" identifier "(" identifier ")"
+  ///                 ( "on" "delete" ( "cascade" | "set" "null" | "restrict" ) )?
+  fn parse_create_list(&self) -> Result<(Vec<Column>, Vec<Vec<String>>), Error> {
         let mut cols_data = Vec::new();
+        let mut unique = Vec::new();
 
         loop {
            let lhs = self.peek();
@@ -387,11 +594,15 @@ impl<'p> Parser<'p>{
                     TokenType::Identifier => {
                         col_name.push_str(&token.lexeme); 
                     },
-                    TokenType::And => continue, //this may need to shift elsewhere
-                    _ => return Err("invalid syntax, expected 'identifier'"), 
+                    TokenType::Unique => {
+                        self.advance();
+                        unique.push(self.parse_column_names()?);
+                        continue;
+                    },
+                    _ => return Err(self.error("invalid syntax, expected 'identifier'")), 
                    } 
                 },
-                None => return Err("Invalid Syntax, expected valid token"),
+                None => return Err(self.error("Invalid Syntax, expected valid token")),
            };
            self.advance();
            let rhs = self.peek();
@@ -400,24 +611,214 @@ impl<'p> Parser<'p>{
             Some(token) => {
                match token.token_type{
                    TokenType::Int | TokenType::VarChar | TokenType::Bool => {
-                       datatype.push_str(&token.lexeme);
+                       datatype.push_str(&token.lexeme.to_lowercase());
                        self.advance();
                    },
-                   _ => return Err("invalid syntax, column requires datatype"), 
+                   _ => return Err(self.error("invalid syntax, column requires datatype")), 
                }
             },
-            None => return Err("invalid syntax, expected valid token")
+            None => return Err(self.error("invalid syntax, expected valid token"))
            };
-           cols_data.push((col_name, datatype));
+
+           let mut column = Column::new(col_name, datatype);
+           self.parse_column_constraints(&mut column)?;
+           cols_data.push(column);
         } 
-       Ok(cols_data)  
+       Ok((cols_data, unique))
+  }
+
+  /// `AUTOINCREMENT = n` after the column list, the largest key the table
+  /// has already handed out. The parser is left on the token after it
+  fn parse_sequence(&self) -> Result<Option<i64>, Error> {
+    if !matches!(self.peek(), Some(Token{token_type: TokenType::Autoincrement, ..})) {
+        return Ok(None);
+    }
+    self.advance();
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::Equal, ..}) => self.advance(),
+        _ => return Err(self.error("invalid syntax, expected '=' after 'autoincrement'")),
+    }
+    match self.peek() {
+        Some(Token{token_type: TokenType::Number, literal: Some(Literal::Number(sequence)), ..}) => {
+            let sequence = *sequence;
+            self.advance();
+            Ok(Some(sequence))
+        },
+        _ => Err(self.error("invalid syntax, expected number after 'autoincrement ='")),
+    }
   }
 
-  /// Update statements and Where conditions have a lhs and a rhs
-  /// as we parse through their list. This function handles this kind
+  /// `BY col [ASC|DESC], ...`, each column along with whether it is sorted
+  /// descending. The parser is left on the token after the last one
+  fn parse_order_by(&self) -> Result<Vec<(String, bool)>, Error> {
+    match self.peek() {
+        Some(Token{token_type: TokenType::By, ..}) => self.advance(),
+        _ => return Err(self.error("invalid syntax, expected 'by' after 'order'")),
+    }
+
+    let mut keys = Vec::new();
+    loop {
+        let column = match self.peek() {
+            Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => lexeme.clone(),
+            _ => return Err(self.error("invalid syntax, expected column name to order by")),
+        };
+        self.advance();
+
+        let descending = match self.peek().map(|token| &token.token_type) {
+            Some(TokenType::Asc) => {
+                self.advance();
+                false
+            },
+            Some(TokenType::Desc) => {
+                self.advance();
+                true
+            },
+            _ => false,
+        };
+        keys.push((column, descending));
+
+        match self.peek() {
+            Some(Token{token_type: TokenType::Comma, ..}) => self.advance(),
+            _ => return Ok(keys),
+        }
+    }
+  }
+
+  fn parse_references(&self) -> Result<ForeignKey, Error> {
+    let table = match self.peek() {
+        Some(Token{token_type: TokenType::Identifier, lexeme, ..}) => lexeme.clone(),
+        _ => return Err(self.error("invalid syntax, expected table name after 'references'")),
+    };
+    self.advance();
+
+    let column = match self.parse_column_names()?.as_slice() {
+        [column] => column.clone(),
+        _ => return Err(self.error("invalid syntax, a reference names a single column")),
+    };
+
+    let mut on_delete = OnDelete::Restrict;
+    if let Some(Token{token_type: TokenType::On, ..}) = self.peek() {
+        self.advance();
+        match self.peek() {
+            Some(Token{token_type: TokenType::Delete, ..}) => self.advance(),
+            _ => return Err(self.error("invalid syntax, expected 'delete' after 'on'")),
+        }
+
+        on_delete = match self.peek().map(|token| &token.token_type) {
+            Some(TokenType::Cascade) => OnDelete::Cascade,
+            Some(TokenType::Restrict) => OnDelete::Restrict,
+            Some(TokenType::Set) => {
+                self.advance();
+                match self.peek() {
+                    Some(Token{token_type: TokenType::Null, ..}) => OnDelete::SetNull,
+                    _ => return Err(self.error("invalid syntax, expected 'null' after 'set'")),
+                }
+            },
+            _ => return Err(self.error("invalid syntax, expected 'cascade', 'set null' or 'restrict'")),
+        };
+        self.advance();
+    }
+
+    Ok(ForeignKey{
+        table,
+        column,
+        on_delete,
+    })
+  }
+
+  /// A parenthesised list of at least one column name, the parser is left
+  /// on the token after the closing parenthesis
+  fn parse_column_names(&self) -> Result<Vec<String>, Error> {
+    match self.peek() {
+        Some(Token{token_type: TokenType::LeftParen, ..}) => {},
+        _ => return Err(self.error("invalid syntax, expected '(' before column list")),
+    }
+
+    let mut columns = Vec::new();
+    self.parse_comma_list(&mut columns)?;
+
+    match self.peek() {
+        Some(Token{token_type: TokenType::RightParen, ..}) if !columns.is_empty() => self.advance(),
+        _ => return Err(self.error("invalid syntax, expected column list")),
+    }
+    Ok(columns)
+  }
+
+  fn parse_column_constraints(&self, column: &mut Column) -> Result<(), Error> {
+    loop {
+        let token = match self.peek() {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        match token.token_type {
+            TokenType::Not => {
+                self.advance();
+                match self.peek() {
+                    Some(Token{token_type: TokenType::Null, ..}) => self.advance(),
+                    _ => return Err(self.error("invalid syntax, expected 'null' after 'not'")),
+                }
+                column.not_null = true;
+            },
+            // the opposite of NOT NULL, which is what columns are anyway
+            TokenType::Null => self.advance(),
+            TokenType::Unique => {
+                self.advance();
+                column.unique = true;
+            },
+            TokenType::References => {
+                self.advance();
+                column.references = Some(self.parse_references()?);
+            },
+            TokenType::Default => {
+                self.advance();
+                match self.peek() {
+                    Some(Token{token_type: TokenType::Number | TokenType::String |
+                                           TokenType::True | TokenType::False | TokenType::Null,
+                               literal: Some(literal), ..}) => {
+                        column.default = Some(literal.clone());
+                        self.advance();
+                    },
+                    _ => return Err(self.error("invalid syntax, expected literal after 'default'")),
+                }
+            },
+            TokenType::Primary => {
+                self.advance();
+                match self.peek() {
+                    Some(Token{token_type: TokenType::Key, ..}) => self.advance(),
+                    _ => return Err(self.error("invalid syntax, expected 'key' after 'primary'")),
+                }
+                column.primary_key = true;
+
+                if let Some(Token{token_type: TokenType::Autoincrement, ..}) = self.peek() {
+                    self.advance();
+                    column.autoincrement = true;
+                }
+            },
+            TokenType::Check => {
+                self.advance();
+                match self.peek() {
+                    Some(Token{token_type: TokenType::LeftParen, ..}) => self.advance(),
+                    _ => return Err(self.error("invalid syntax, expected '(' after 'check'")),
+                }
+
+                column.check = Some(self.parse_expression()?);
+
+                match self.peek() {
+                    Some(Token{token_type: TokenType::RightParen, ..}) => self.advance(),
+                    _ => return Err(self.error("invalid syntax, expected ')' after check constraint")),
+                }
+            },
+            _ => return Ok(()),
+        }
+    }
+  }
+
+  /// The SET list of an update statement has a lhs and a rhs
+  /// as we parse through it. This function handles this kind
   /// of parsing
-  
-  fn parse_equality_list(&self) -> Result<(Vec<String>, Vec<Literal>), &str>{ 
+  fn parse_equality_list(&self) -> Result<(Vec<String>, Vec<Literal>), Error>{ 
     let mut cols = Vec::new();
     let mut vals = Vec::new();
 
@@ -435,10 +836,10 @@ impl<'p> Parser<'p>{
                     self.advance();
                     continue;
                 }, 
-                _ => return Err("invalid syntax, expected 'identifier'"), 
+                _ => return Err(self.error("invalid syntax, expected 'identifier'")), 
                } 
             },
-            None => return Err("Invalid Syntax, expected valid token"),
+            None => return Err(self.error("Invalid Syntax, expected valid token")),
        };
        self.advance();
        let expect_equal = self.peek();
@@ -447,10 +848,10 @@ impl<'p> Parser<'p>{
         Some(token)  => {
             match token.token_type {
                 TokenType::Equal => self.advance(),
-                _ => return Err("invalid syntax, expected '='"), 
+                _ => return Err(self.error("invalid syntax, expected '='")), 
            }; 
         },
-        None => return Err("invalid syntax, expected token"),
+        None => return Err(self.error("invalid syntax, expected token")),
        };
 
        let rhs = self.peek();
@@ -458,17 +859,17 @@ impl<'p> Parser<'p>{
        match rhs {
         Some(token) => {
            match token.token_type{
-            TokenType::Number | TokenType::True | TokenType::False | TokenType::String => {
+            TokenType::Number | TokenType::True | TokenType::False | TokenType::String | TokenType::Null => {
                     if let Some(literal) = &token.literal { 
                         vals.push(literal.clone());  
                     } else {
-                        return Err("invalid syntax, expected literal");
+                        return Err(self.error("invalid syntax, expected literal"));
                     }
                 },
-            _ => return Err("invalid syntax, expected literal"),
+            _ => return Err(self.error("invalid syntax, expected literal")),
            }
         },
-        None => return Err("invalid syntax, expected valid token")
+        None => return Err(self.error("invalid syntax, expected valid token"))
        };
 
         if let Some(table_token) = self.peek_next(){
@@ -481,7 +882,133 @@ impl<'p> Parser<'p>{
        Ok((cols, vals))
   }
 
-    fn parse_comma_list_literal(&self, target_vals: &mut Vec<Literal>) -> Result<(), &str> {
+  /// Where clauses are parsed by recursive descent so that NOT binds
+  /// tighter than AND, which in turn binds tighter than OR:
+  ///
+  ///   expression -> or
+  ///   or         -> and ( "or" and )*
+  ///   and        -> not ( "and" not )*
+  ///   not        -> "not" not | comparison
+  ///   comparison -> primary ( ( "=" | "<>" | "<" | "<=" | ">" | ">=" ) primary
+  ///                          | "is" "not"? "null" )?
+  ///   primary    -> literal | identifier | "(" expression ")"
+  fn parse_expression(&self) -> Result<Expr, Error>{
+    self.or_expr()
+  }
+
+  fn or_expr(&self) -> Result<Expr, Error>{
+    let mut expr = self.and_expr()?;
+
+    while let Some(Token{token_type: TokenType::Or, ..}) = self.peek(){
+        self.advance();
+        let right = self.and_expr()?;
+        expr = Expr::Binary{
+            left: Box::new(expr),
+            operator: TokenType::Or,
+            right: Box::new(right),
+        };
+    }
+    Ok(expr)
+  }
+
+  fn and_expr(&self) -> Result<Expr, Error>{
+    let mut expr = self.not_expr()?;
+
+    while let Some(Token{token_type: TokenType::And, ..}) = self.peek(){
+        self.advance();
+        let right = self.not_expr()?;
+        expr = Expr::Binary{
+            left: Box::new(expr),
+            operator: TokenType::And,
+            right: Box::new(right),
+        };
+    }
+    Ok(expr)
+  }
+
+  fn not_expr(&self) -> Result<Expr, Error>{
+    if let Some(Token{token_type: TokenType::Not, ..}) = self.peek(){
+        self.advance();
+        let right = self.not_expr()?;
+        return Ok(Expr::Unary{
+            operator: TokenType::Not,
+            right: Box::new(right),
+        });
+    }
+    self.comparison()
+  }
+
+  fn comparison(&self) -> Result<Expr, Error>{
+    let left = self.primary()?;
+
+    if let Some(token) = self.peek(){
+        match token.token_type {
+            TokenType::Equal | TokenType::NotEqual |
+            TokenType::Less | TokenType::LessEqual |
+            TokenType::Greater | TokenType::GreaterEqual => {
+                self.advance();
+                let right = self.primary()?;
+                return Ok(Expr::Binary{
+                    left: Box::new(left),
+                    operator: token.token_type.clone(),
+                    right: Box::new(right),
+                });
+            },
+            TokenType::Is => {
+                self.advance();
+                let negated = matches!(self.peek(), Some(Token{token_type: TokenType::Not, ..}));
+                if negated {
+                    self.advance();
+                }
+
+                match self.peek(){
+                    Some(Token{token_type: TokenType::Null, ..}) => self.advance(),
+                    _ => return Err(self.error("invalid syntax, expected 'null' after 'is'")),
+                }
+                return Ok(Expr::IsNull{
+                    expr: Box::new(left),
+                    negated,
+                });
+            },
+            _ => {},
+        }
+    }
+    Ok(left)
+  }
+
+  fn primary(&self) -> Result<Expr, Error>{
+    let token = match self.peek(){
+        Some(token) => token,
+        None => return Err(self.error("invalid syntax, expected expression")),
+    };
+
+    match token.token_type {
+        TokenType::Number | TokenType::String | TokenType::True | TokenType::False | TokenType::Null => {
+            let literal = match &token.literal {
+                Some(literal) => literal.clone(),
+                None => return Err(self.error("invalid syntax, expected literal")),
+            };
+            self.advance();
+            Ok(Expr::Literal(literal))
+        },
+        TokenType::Identifier => {
+            self.advance();
+            Ok(Expr::Column(token.lexeme.clone()))
+        },
+        TokenType::LeftParen => {
+            self.advance();
+            let expr = self.parse_expression()?;
+            match self.peek(){
+                Some(Token{token_type: TokenType::RightParen, ..}) => self.advance(),
+                _ => return Err(self.error("invalid syntax, expected ')' after expression")),
+            }
+            Ok(Expr::Grouping(Box::new(expr)))
+        },
+        _ => Err(self.error("invalid syntax, expected column or literal in expression")),
+    }
+  }
+
+    fn parse_comma_list_literal(&self, target_vals: &mut Vec<Literal>) -> Result<(), Error> {
         loop {
             let current_token = self.peek();
 
@@ -489,26 +1016,27 @@ impl<'p> Parser<'p>{
                 Some(token) => {
                     match token.token_type {
                         TokenType::From | TokenType::RightParen => break, 
-                        TokenType::Number | TokenType::String | TokenType::True | TokenType::False => {
-                            if let Some(literal) = &token.literal{
-                                target_vals.push(literal.clone()); 
+                        TokenType::Number | TokenType::String | TokenType::True | TokenType::False | TokenType::Null => {
+                            match &token.literal {
+                                Some(literal) => target_vals.push(literal.clone()),
+                                None => return Err(self.error("Invalid syntax, expected literal")),
                             }
                         },
                         TokenType::Comma | TokenType::LeftParen => {
                             self.advance();
                             continue;
                         },
-                        _ => return Err("Invalid syntax, expected literal"),
+                        _ => return Err(self.error("Invalid syntax, expected literal")),
                     }
                 }
-                None => return Err("Invalid syntax, expected valid token"),
+                None => return Err(self.error("Invalid syntax, expected valid token")),
             }
             self.advance();
         };
         Ok(())
       }
 
-    fn parse_comma_list(&self, target_columns: &mut Vec<String>) -> Result<(), &str> {
+    fn parse_comma_list(&self, target_columns: &mut Vec<String>) -> Result<(), Error> {
         loop {
             let current_token = self.peek();
 
@@ -523,10 +1051,10 @@ impl<'p> Parser<'p>{
                             self.advance();
                             continue;
                         },
-                        _ => return Err("Invalid syntax, expected column name"),
+                        _ => return Err(self.error("Invalid syntax, expected column name")),
                     }
                 }
-                None => return Err("Invalid syntax, expected valid token"),
+                None => return Err(self.error("Invalid syntax, expected valid token")),
             }
             self.advance();
         };
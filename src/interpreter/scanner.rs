@@ -1,4 +1,5 @@
 use crate::interpreter::token::{TokenType, Token, Literal};
+use crate::error::Error;
 
 #[derive(Debug)]
 pub struct Scanner<'s>{
@@ -20,7 +21,7 @@ impl<'s> Scanner<'s>{
             
             match self_slice.chars().next() {
                 Some(char) => {
-                    self.position += 1;
+                    self.position += char.len_utf8();
                     return Some(char)
                 }
                 None => return Some('\0') //temp
@@ -32,7 +33,6 @@ impl<'s> Scanner<'s>{
     /// peeking ahead will help us identify certain
     /// tokens
     /// Looks at next char without moving position forward
-
     fn peek(&self) -> Option<char>{
         if self.position == self.cmd.len(){
            return Some('\0')
@@ -40,19 +40,49 @@ impl<'s> Scanner<'s>{
        self.cmd[self.position..].chars().next() 
     }
 
+    /// Looks one char past the current position, used for
+    /// two character operators such as '<=' and '<>'
+    fn peek_next(&self) -> Option<char>{
+        let mut chars = self.cmd[self.position..].chars();
+        chars.next();
+        chars.next()
+    }
+
+    /// Characters that end an alphanumeric sequence outside of a
+    /// string and are then scanned as tokens of their own
+    fn is_delimiter(&self, c: char) -> bool {
+        matches!(c, ',' | '(' | ')' | ';' | '.' | '=' | '<' | '>' | '!')
+    }
+
+    /// Positions are reported in characters rather than bytes so they
+    /// line up with what the user typed
+    fn char_position(&self) -> usize {
+        self.cmd[..self.position].chars().count()
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::Syntax{
+            message: String::from(message),
+            position: self.char_position(),
+        }
+    }
+
      fn is_alphanumeric(&self, key: &str) -> bool {
         key.trim().chars().filter(|w| !w.is_whitespace()).all(|c| c.is_alphanumeric())
     }
 
+     /// Numbers may be negative, as in `-5`
      fn is_numeric(&self, key: &str) -> bool{
-        key.chars().all(|c| c.is_numeric())
+        match key.strip_prefix('-') {
+            Some(digits) => !digits.is_empty() && digits.chars().all(|c| c.is_numeric()),
+            None => key.chars().all(|c| c.is_numeric()),
+        }
     }
     
     /// This function handles scanning the input string itself,
     /// identifying keys to be turned into tokens for the parser to 
     /// verify in the following step
-
-     pub fn scan(&mut self) -> Result<Vec<Token>, &str>{
+     pub fn scan(&mut self) -> Result<Vec<Token>, Error>{
         let mut tokens: Vec<Token> = Vec::new();
         
         loop{
@@ -66,50 +96,61 @@ impl<'s> Scanner<'s>{
                     }
                     val
                 },
-                None => return Err("invalid sequence"),
+                None => return Err(self.error("invalid sequence")),
             };
             
-            //for now only single line
             if curr == '\0'{
                 break;
             }
 
+            // comments run to the end of the line
+            if curr == '-' && self.peek_next() == Some('-') {
+                while !matches!(self.advance(), Some('\n') | Some('\0') | None) {}
+                continue;
+            }
+
+            let start = self.char_position();
+
             if curr == ';'{
                 let token_type = TokenType::SemiColon;
-                let new_token = Token::new(token_type, String::from(curr), None);
+                let new_token = Token::new(token_type, String::from(curr), None, start);
                 tokens.push(new_token);
-                break;
-            }
-
-            if curr.is_alphabetic() || curr.is_numeric() || curr == '\'' {
+            } else if curr.is_alphabetic() || curr.is_numeric() || curr == '\'' ||
+                (curr == '-' && self.peek_next().is_some_and(|next| next.is_numeric())) {
                 //handle alphanumeric
-                let new_token = match self.scan_alphanumeric_sequence(){
-                    Ok(token) => token,
-                    Err(_) => return Err("invalid sequence, expected alphanumeric sequence"),
-                };
+                let new_token = self.scan_alphanumeric_sequence(start)?;
                 
                 tokens.push(new_token);
 
-                let check_curr = self.peek();
-                if check_curr.is_some() && (check_curr.unwrap() == ',' 
-                                            || check_curr.unwrap() == ')'
-                                            || check_curr.unwrap() == ';'){
-                    // if we have a comma after an alphanumeric, we want to
-                    // process it separately
-                    continue;
-                } else if check_curr.is_some() && check_curr.unwrap() == '\0'{
-                    break;
+                match self.peek() {
+                    // if we have a comma, parenthesis or operator after an
+                    // alphanumeric, we want to process it separately
+                    Some(next) if self.is_delimiter(next) => continue,
+                    Some('\0') => break,
+                    _ => {},
                 }
             }else {
-                let input = String::from(curr); 
+                let mut input = String::from(curr); 
+
+                // comparison operators may span two characters
+                match (curr, self.peek_next()) {
+                    ('<', Some('=')) | ('>', Some('=')) | ('!', Some('=')) | ('<', Some('>')) => {
+                        self.advance();
+                        input.push(self.peek().unwrap_or('\0'));
+                    },
+                    _ => {},
+                }
 
                 let token_type = match self.get_tokentype(&input, false){
                         Some(t_type) => t_type,
-                        None => return Err("invalid token"),
+                        None => return Err(Error::Syntax{
+                            message: format!("invalid token '{}'", input),
+                            position: start,
+                        }),
                 };
-                let literal_type = self.get_literal_type(&input, false);
+                let literal_type = self.get_literal_type(&input, false, start)?;
                 
-                let new_token = Token::new(token_type, input, literal_type);
+                let new_token = Token::new(token_type, input, literal_type, start);
                 tokens.push(new_token); 
             }
             
@@ -121,7 +162,7 @@ impl<'s> Scanner<'s>{
         Ok(tokens)
     }
 
-    fn scan_alphanumeric_sequence(&mut self) -> Result<Token, &str>{
+    fn scan_alphanumeric_sequence(&mut self, start: usize) -> Result<Token, Error>{
         let mut coll = String::new();
         let mut open_string = false;
         let mut is_string = false;
@@ -129,27 +170,27 @@ impl<'s> Scanner<'s>{
             let curr = self.peek();
 
             match curr{
-                Some(curr_char) if curr_char.is_whitespace() && open_string == false || 
-                    curr_char == '\0' ||
-                    curr_char == ',' || 
-                    curr_char == '(' ||
-                    curr_char == ')' ||
-                    curr_char == ';' => {
+                Some(curr_char) if !open_string && (curr_char.is_whitespace() || self.is_delimiter(curr_char)) ||
+                    curr_char == '\0' => {
                     break
                 },
                 Some(curr_char) if curr_char == '\'' => {
-                    if  open_string == false {
+                    if !open_string {
                         open_string = true;
                         is_string = true;
                         self.advance();
                         continue;
+                    } else if self.peek_next() == Some('\'') {
+                        // a doubled quote inside a string is an escaped quote
+                        coll.push(curr_char);
+                        self.advance();
                     } else {
                         open_string = false;
                         break;
                     }
                 },
                 Some(curr_char) => coll.push(curr_char),
-                None => return Err("invalid character detected"),
+                None => return Err(self.error("invalid character detected")),
             }
             match self.advance(){
                 Some('\0') | None => break,
@@ -157,32 +198,42 @@ impl<'s> Scanner<'s>{
             }
         }
        
-        if open_string == true {
-            let literal_type = self.get_literal_type(&coll, is_string); 
-            return Ok(Token::new(TokenType::String, coll, literal_type));
+        if open_string {
+            let literal_type = self.get_literal_type(&coll, is_string, start)?;
+            return Ok(Token::new(TokenType::String, coll, literal_type, start));
         }
 
         let token_type = match self.get_tokentype(&coll, is_string){
             Some(t_type) => t_type,
-            None => return Err("invalid token"),
+            None => return Err(Error::Syntax{
+                message: format!("invalid token '{}'", coll),
+                position: start,
+            }),
         };
 
-        let literal_type = self.get_literal_type(&coll, is_string);
+        let literal_type = self.get_literal_type(&coll, is_string, start)?;
         
-        let new_token = Token::new(token_type, coll, literal_type);
+        let new_token = Token::new(token_type, coll, literal_type, start);
         Ok(new_token)
     }
     
      /// Pretty self explanatory, we pass in a constructed
-     /// part of the SQL command so we can identify the type
+     /// part of the SQL command so we can identify the type.
+     /// Keywords are matched regardless of case, while the lexeme
+     /// itself is left untouched so identifiers keep their spelling
      fn get_tokentype(&self, keyword: &str, is_string: bool) -> Option<TokenType>{
-        match keyword{
+        if is_string {
+            return Some(TokenType::String);
+        }
+
+        match keyword.to_lowercase().as_str(){
             "select" => Some(TokenType::Select),
             "*" => Some(TokenType::All),
             "from" => Some(TokenType::From),
             "where" => Some(TokenType::Where),
             "create" => Some(TokenType::Create),
             "table" => Some(TokenType::Table),
+            "index" => Some(TokenType::Index),
             "insert" => Some(TokenType::Insert),
             "into" => Some(TokenType::Into),
             "update" => Some(TokenType::Update),
@@ -190,25 +241,63 @@ impl<'s> Scanner<'s>{
             "set" => Some(TokenType::Set),
             "delete" => Some(TokenType::Delete),
             "drop" => Some(TokenType::Drop),
+            "attach" => Some(TokenType::Attach),
+            "detach" => Some(TokenType::Detach),
+            "database" => Some(TokenType::Database),
+            "as" => Some(TokenType::As),
+            "begin" => Some(TokenType::Begin),
+            "commit" => Some(TokenType::Commit),
+            "rollback" => Some(TokenType::Rollback),
+            "transaction" => Some(TokenType::Transaction),
+            "savepoint" => Some(TokenType::Savepoint),
+            "explain" => Some(TokenType::Explain),
+            "query" => Some(TokenType::Query),
+            "plan" => Some(TokenType::Plan),
+            "limit" => Some(TokenType::Limit),
+            "order" => Some(TokenType::Order),
+            "by" => Some(TokenType::By),
+            "asc" => Some(TokenType::Asc),
+            "desc" => Some(TokenType::Desc),
+            "release" => Some(TokenType::Release),
+            "to" => Some(TokenType::To),
             "int" => Some(TokenType::Int),
             "varchar" => Some(TokenType::VarChar),
             "bool" => Some(TokenType::Bool),
             "=" => Some(TokenType::Equal),
             "equals" => Some(TokenType::Equal),
+            "<>" => Some(TokenType::NotEqual),
+            "!=" => Some(TokenType::NotEqual),
+            "<" => Some(TokenType::Less),
+            "<=" => Some(TokenType::LessEqual),
+            ">" => Some(TokenType::Greater),
+            ">=" => Some(TokenType::GreaterEqual),
             "(" => Some(TokenType::LeftParen),
             ")" => Some(TokenType::RightParen),
             "\'" => Some(TokenType::String), //this needs to handle contents
             "and" => Some(TokenType::And),
+            "or" => Some(TokenType::Or),
+            "not" => Some(TokenType::Not),
+            "is" => Some(TokenType::Is),
+            "default" => Some(TokenType::Default),
+            "check" => Some(TokenType::Check),
+            "primary" => Some(TokenType::Primary),
+            "key" => Some(TokenType::Key),
+            "autoincrement" => Some(TokenType::Autoincrement),
+            "unique" => Some(TokenType::Unique),
+            "references" => Some(TokenType::References),
+            "on" => Some(TokenType::On),
+            "cascade" => Some(TokenType::Cascade),
+            "restrict" => Some(TokenType::Restrict),
             "false" => Some(TokenType::False),
             "true" => Some(TokenType::True),
+            "null" => Some(TokenType::Null),
             "," => Some(TokenType::Comma),
+            "." => Some(TokenType::Dot),
             ";" => Some(TokenType::SemiColon),
             "\0" => Some(TokenType::EOF), //error
             _ => {
-               if self.is_numeric(keyword) && !is_string {
+               if self.is_numeric(keyword) {
                     Some(TokenType::Number) //will need to expand for float
-               } else if is_string { 
-                    Some(TokenType::String) 
                } else if self.is_alphanumeric(keyword){
                     Some(TokenType::Identifier) 
                } else {
@@ -218,22 +307,34 @@ impl<'s> Scanner<'s>{
         }
     }
 
-    fn get_literal_type(&self, literal: &str, is_string: bool) -> Option<Literal>{
-        
-        if self.is_numeric(literal) && !is_string{
-            Some(Literal::Number(literal.parse::<i64>().unwrap()))
-        } else if self.is_alphanumeric(literal) && is_string {
-            Some(Literal::String(String::from(literal)))
-        } else if literal == "true" && !is_string{
+    /// String contents are kept exactly as typed, everything else
+    /// is matched without regard to case. A number too large to be
+    /// held is refused rather than dropped
+    fn get_literal_type(&self, literal: &str, is_string: bool, start: usize) -> Result<Option<Literal>, Error>{
+        if is_string {
+            return Ok(Some(Literal::String(String::from(literal))));
+        }
+
+        let lowered = literal.to_lowercase();
+        let literal = if self.is_numeric(literal){
+            match literal.parse::<i64>() {
+                Ok(val) => Some(Literal::Number(val)),
+                Err(_) => return Err(Error::Syntax{
+                    message: String::from("integer literal out of range"),
+                    position: start,
+                }),
+            }
+        } else if lowered == "true" {
             Some(Literal::Boolean(true))
-        } else if literal == "false" && !is_string { 
+        } else if lowered == "false" { 
             Some(Literal::Boolean(false))
-        } else if literal == "null" {
+        } else if lowered == "null" {
             Some(Literal::Null)
         } else {
             None
-        }
-    } 
+        };
+        Ok(literal)
+    }
 }
 
 
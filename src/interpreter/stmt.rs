@@ -1,34 +1,132 @@
-use crate::interpreter::token::{Literal};
+use std::fmt;
 
+use crate::interpreter::token::{Literal, TokenType};
+use crate::vm::table::Column;
+
+/// Boolean expression tree used by WHERE clauses and CHECK constraints.
+/// Binary nodes carry the operator token type (And, Or or a comparison) and
+/// are evaluated per row by the VM
+#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
+#[derive(Debug, Clone)]
+pub enum Expr{
+   Binary{
+        left: Box<Expr>,
+        operator: TokenType,
+        right: Box<Expr>,
+   },
+   Unary{
+        operator: TokenType,
+        right: Box<Expr>,
+   },
+   /// `expr IS NULL`, or `expr IS NOT NULL` when negated
+   IsNull{
+        expr: Box<Expr>,
+        negated: bool,
+   },
+   Grouping(Box<Expr>),
+   Column(String),
+   Literal(Literal),
+}
+
+/// Table names may be qualified with the name of an
+/// attached database, as in `other.table`
 #[derive(Debug, Clone)]
 pub enum Stmt{
    Select{
         table_name: String,
         target_columns: Vec<String>,
-        where_conditions: Option<(Vec<String>, Vec<Literal>)>,
+        where_clause: Option<Expr>,
+        /// Columns to sort by in turn, descending when the flag is set
+        order_by: Vec<(String, bool)>,
+        limit: Option<usize>,
    },
    Insert{
         table_name: String,
         target_columns: Vec<String>,
-        target_values: Vec<Literal>, 
+        target_values: Vec<Literal>,
    },
    Create{
         table_name: String,
-        columns_and_data: Vec<(String, String)>,
+        columns: Vec<Column>,
+        /// UNIQUE constraints over lists of columns
+        unique: Vec<Vec<String>>,
+        /// The largest AUTOINCREMENT key already handed out, set when
+        /// a dumped table is rebuilt
+        sequence: Option<i64>,
     },
    Drop{
         table_name: String,
    },
-   Delete{ 
+   CreateIndex{
+        index_name: String,
+        table_name: String,
+        columns: Vec<String>,
+        unique: bool,
+   },
+   /// The index name may be qualified like a table name
+   DropIndex{
+        index_name: String,
+   },
+   Delete{
         table_name: String,
-        lhs: Vec<String>,
-        rhs: Vec<Literal>,
+        where_clause: Expr,
    },
    Update{
         table_name: String,
-        where_col: Vec<String>, 
-        where_val: Vec<Literal>, 
+        where_clause: Expr,
         target_columns: Vec<String>,
-        target_values: Vec<Literal>, 
+        target_values: Vec<Literal>,
+   },
+   Attach{
+        path: String,
+        schema_name: String,
+   },
+   Detach{
+        schema_name: String,
+   },
+   Begin,
+   Commit,
+   Rollback,
+   Savepoint{
+        name: String,
+   },
+   Release{
+        name: String,
    },
+   RollbackTo{
+        name: String,
+   },
+   /// Describes how a statement would run instead of running it
+   Explain{
+        query_plan: bool,
+        statement: Box<Stmt>,
+   },
+}
+
+/// Writes the expression back out as SQL
+impl fmt::Display for Expr{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Expr::Binary{left, operator, right} => {
+                let operator = match operator {
+                    TokenType::And => "AND",
+                    TokenType::Or => "OR",
+                    TokenType::Equal => "=",
+                    TokenType::NotEqual => "<>",
+                    TokenType::Less => "<",
+                    TokenType::LessEqual => "<=",
+                    TokenType::Greater => ">",
+                    _ => ">=",
+                };
+                write!(f, "{} {} {}", left, operator, right)
+            },
+            Expr::Unary{right, ..} => write!(f, "NOT {}", right),
+            Expr::IsNull{expr, negated: false} => write!(f, "{} IS NULL", expr),
+            Expr::IsNull{expr, negated: true} => write!(f, "{} IS NOT NULL", expr),
+            Expr::Grouping(inner) => write!(f, "({})", inner),
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Literal(val) => write!(f, "{}", val),
+        }
+    }
 }
@@ -1,25 +1,38 @@
 #![allow(dead_code)]
 
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
 #[derive(Debug, Clone)]
 pub enum TokenType {
     // keywords 
     Select, All, From, Where, Create, TableName, 
     Insert, Into, Update, Delete, Drop, Set, Values,  
+    Attach, Detach, Database, As,
+    Begin, Commit, Rollback, Transaction, Savepoint, Release, To,
+    Explain, Query, Plan, Limit, Order, By, Asc, Desc,
     
     //datatypes
     Int, VarChar, Bool,
 
     //punctuation
-    LeftParen, RightParen, Comma, SemiColon, Asterisk, Quote,
+    LeftParen, RightParen, Comma, SemiColon, Asterisk, Quote, Dot,
 
     //ops
-    Equal, And, 
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    And, Or, Not, Is,
+
+    //constraints
+    Default, Check, Primary, Key, Autoincrement, Unique,
+    References, On, Cascade, Restrict,
 
     //Literals
-    Number, String, True, False, 
+    Number, String, True, False, Null,
 
     //Identifier
-    Identifier, Column, Table,
+    Identifier, Column, Table, Index,
 
     EOF
 }
@@ -45,19 +58,48 @@ impl PartialEq<i64> for Literal{
     }
 }
 
+/// Writes the literal the way it is typed in a statement,
+/// quotes inside strings are doubled
+impl fmt::Display for Literal{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Literal::Number(val) => write!(f, "{}", val),
+            Literal::String(val) => write!(f, "'{}'", val.replace('\'', "''")),
+            Literal::Boolean(val) => write!(f, "{}", val),
+            Literal::Null | Literal::None => write!(f, "NULL"),
+        }
+    }
+}
+
+/// Literals of the same type are ordered numerically, lexicographically
+/// or false before true, values of different types cannot be compared
+impl PartialOrd for Literal{
+    fn partial_cmp(&self, other: &Literal) -> Option<Ordering>{
+        match (self, other){
+            (Literal::Number(lhs), Literal::Number(rhs)) => lhs.partial_cmp(rhs),
+            (Literal::String(lhs), Literal::String(rhs)) => lhs.partial_cmp(rhs),
+            (Literal::Boolean(lhs), Literal::Boolean(rhs)) => lhs.partial_cmp(rhs),
+            (Literal::Null, Literal::Null) => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub literal: Option<Literal>,
+    pub position: usize,
 }
 
 impl Token{
-    pub fn new(token_type: TokenType, lexeme: String, literal:Option<Literal>) -> Self{
+    pub fn new(token_type: TokenType, lexeme: String, literal:Option<Literal>, position: usize) -> Self{
         Token{
             token_type,
             lexeme,
             literal,
+            position,
         }
     }
 }
@@ -0,0 +1,10 @@
+pub mod vm;
+pub mod interpreter;
+mod storage;
+mod connection;
+mod error;
+
+pub use connection::Connection;
+pub use error::Error;
+pub use interpreter::token::Literal;
+pub use vm::Rows;
@@ -1,54 +1,280 @@
-use std::io::{self, Write};
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::process;
 use std::thread;
 use std::time::Duration;
 use colored::*;
 
-pub mod vm;
-pub mod interpreter;
+mod csv;
+mod dump;
+mod render;
+
+use rustqlite::{vm, interpreter, Error};
+use rustqlite::interpreter::stmt::Stmt;
+use rustqlite::vm::{Outcome, VirtualMachine};
 
 fn main() {
-    println!("Starting RUSTQLITE...");
+    // banners and prompts are only shown to a person at a terminal,
+    // not when a script is piped in as in `rustqlite db.rdb < script.sql`
+    let interactive = io::stdin().is_terminal();
+
+    if interactive{
+        println!("Starting RUSTQLITE...");
+
+        thread::sleep(Duration::from_secs(1)); 
+        print_title();    
+    }
+
+    // the database can be given as the first argument, `:memory:`
+    // keeps it in memory for the length of the session
+    let db_path = env::args().nth(1).unwrap_or_else(|| String::from(vm::DEFAULT_DB_PATH));
+
+    if interactive && db_path != vm::MEMORY_PATH && !Path::new(&db_path).exists(){
+        println!("{}", "No database found.. creating new DB instance".yellow());
+    }
+
+    let mut machine = match VirtualMachine::open(Path::new(&db_path)){
+        Ok(machine) => machine,
+        Err(err) => {
+            println!("{}", err.to_string().red());
+            process::exit(1);
+        },
+    };
+
+    // input is collected until it holds complete statements,
+    // so a statement may be spread over several lines
+    let mut buffer = String::new();
+    let mut output = render::Output::default();
+    // a piped script stops at the first statement or command that fails
+    let mut failed = false;
 
-    thread::sleep(Duration::from_secs(1)); 
-    print_title();    
     loop{
-        
-        print!("RQLITE > ");
-        io::stdout().flush().unwrap();
-        let mut cmd = String::new();
+        if interactive{
+            let prompt = if buffer.is_empty() { "RQLITE > " } else { "   ...> " };
+            print!("{}", prompt);
+            io::stdout().flush().unwrap();
+        }
+
+        let mut line = String::new();
 
-        if io::stdin().read_line(&mut cmd).is_err(){
-            println!("{}", "Failed to read input".red());
-            continue;
+        match io::stdin().read_line(&mut line){
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(_) => {
+                println!("{}", "Failed to read input".red());
+                continue;
+            },
         }
         
-        let trimmed = cmd.trim().to_lowercase();
+        // only the REPL commands are matched without regard to case,
+        // SQL is handed to the interpreter as typed so data keeps its case
+        let trimmed = line.trim();
+
+        if buffer.is_empty(){
+            if trimmed.eq_ignore_ascii_case("exit") || trimmed.eq_ignore_ascii_case(".exit"){
+                break;
+            }
 
-        if trimmed == "exit"{
-            println!("exiting...");
+            if trimmed.eq_ignore_ascii_case("schema") || trimmed.starts_with('.'){
+                failed = !run_command(&mut machine, &mut output, trimmed);
+                if failed && !interactive{
+                    break;
+                }
+                continue;
+            }
+        }
+
+        buffer.push_str(&line);
+
+        let (complete, rest) = interpreter::split_complete(&buffer);
+        failed = !run_statements(&mut machine, &output, complete);
+        buffer = rest.to_string();
+
+        if failed && !interactive{
+            buffer.clear();
             break;
         }
+    }
+
+    if !buffer.trim().is_empty(){
+        println!("{}", "Incomplete statement at end of input, missing ';'".red());
+    }
+
+    rollback_open(&mut machine);
+
+    if interactive{
+        println!("exiting...");
+    }
+
+    if failed && !interactive{
+        process::exit(1);
+    }
+}
 
-        if trimmed == "schema"{
-            if let Err(err) = vm::print_schema(){
-                println!("{}", err);
+/// A script that stops part way must not leave its transaction open,
+/// or the statements before the failure could still be committed
+fn rollback_open(machine: &mut VirtualMachine){
+    if machine.in_transaction() && machine.run(&Stmt::Rollback).is_ok(){
+        println!("{}", "Open transaction rolled back".yellow());
+    }
+}
+
+/// REPL commands, which take up a single line and need no terminator.
+/// Returns whether the command succeeded
+fn run_command(machine: &mut VirtualMachine, output: &mut render::Output, line: &str) -> bool{
+    let (command, arg) = match line.split_once(char::is_whitespace){
+        Some((command, arg)) => (command, arg.trim()),
+        None => (line, ""),
+    };
+
+    match command.to_lowercase().as_str() {
+        "schema" | ".schema" => {
+            match machine.table_names(){
+                Ok(names) => {
+                    for name in names{
+                        println!("{}", name.blue());
+                    }
+                    true
+                },
+                Err(err) => fail(&err.to_string()),
             }
-            continue;
-        }
+        },
+        ".read" if !arg.is_empty() => {
+            let script = match fs::read_to_string(arg){
+                Ok(script) => script,
+                Err(err) => return fail(&format!("Unable to read {}: {}", arg, err)),
+            };
 
-        //this may not be the right way to go about this
-        let statement = interpreter::interpret(&trimmed);
+            let (complete, rest) = interpreter::split_complete(&script);
+            if !run_statements(machine, output, complete){
+                rollback_open(machine);
+                return false;
+            }
+            if !rest.trim().is_empty(){
+                rollback_open(machine);
+                return fail(&format!("Incomplete statement at end of {}, missing ';'", arg));
+            }
+            true
+        },
+        ".read" => fail("Usage: .read <file>"),
+        ".import" => {
+            match arg.split_whitespace().collect::<Vec<&str>>()[..] {
+                [path, table_name] => match csv::import(machine, path, table_name){
+                    Ok(count) => {
+                        println!("{}", format!("Imported {} row(s) into {}", count, table_name).green());
+                        true
+                    },
+                    Err(err) => fail(&err.to_string()),
+                },
+                _ => fail("Usage: .import <file> <table>"),
+            }
+        },
+        ".export" => {
+            match arg.split_whitespace().collect::<Vec<&str>>()[..] {
+                [table_name, path] => match csv::export(machine, table_name, path){
+                    Ok(count) => {
+                        println!("{}", format!("Exported {} row(s) to {}", count, path).green());
+                        true
+                    },
+                    Err(err) => fail(&err.to_string()),
+                },
+                _ => fail("Usage: .export <table> <file>"),
+            }
+        },
+        ".dump" => {
+            let table_name = if arg.is_empty() { None } else { Some(arg) };
+            let mut out = BufWriter::new(io::stdout().lock());
 
-        match statement {
-            Ok(stmt) => {
-                match vm::process(stmt.clone()){
-                    Ok(msg) => println!("{}", msg),
-                    Err(err) => println!("{}", err),
-                }
+            match dump::dump(machine, table_name, &mut out){
+                Ok(()) => true,
+                Err(err) => {
+                    drop(out);
+                    fail(&err.to_string())
+                },
+            }
+        },
+        ".mode" if arg.is_empty() => {
+            println!("current output mode: {}", output.mode.name());
+            true
+        },
+        ".mode" => {
+            match render::Mode::from_name(arg){
+                Some(mode) => {
+                    output.mode = mode;
+                    true
+                },
+                None => fail(&format!("Unknown mode '{}', expected one of: {}", arg, render::Mode::NAMES)),
+            }
+        },
+        ".headers" => {
+            match arg.to_lowercase().as_str() {
+                "on" => output.headers = true,
+                "off" => output.headers = false,
+                _ => return fail("Usage: .headers on|off"),
+            }
+            true
+        },
+        _ => fail(&format!("Unknown command '{}'", command)),
+    }
+}
+
+/// Prints an error in red, returns false so a failing command can end with it
+fn fail(message: &str) -> bool{
+    println!("{}", message.red());
+    false
+}
+
+/// Runs every statement in `input` in order and stops at the first one that
+/// fails, returns whether all of them succeeded. Nothing runs when any of
+/// the statements has a syntax error
+fn run_statements(machine: &mut VirtualMachine, output: &render::Output, input: &str) -> bool{
+    let statements = match interpreter::interpret(input){
+        Ok(statements) => statements,
+        Err(err) => {
+            println!("{}", describe(&err, input).red());
+            return false;
+        },
+    };
+
+    for (idx, stmt) in statements.iter().enumerate(){
+        match machine.run(stmt){
+            Ok(Outcome::Rows(rows)) => {
+                let table_name = match stmt {
+                    Stmt::Select{table_name, ..} => table_name.as_str(),
+                    Stmt::Explain{query_plan: true, ..} => "query plan",
+                    Stmt::Explain{..} => "explain",
+                    _ => "",
+                };
+                print!("{}", output.render(table_name, &rows));
+            },
+            Ok(Outcome::Done{message, ..}) => println!("{}", message.green()),
+            Err(err) if statements.len() > 1 => {
+                println!("{}", format!("{} (in statement {} of {})", err, idx + 1, statements.len()).red());
+                return false;
+            },
+            Err(err) => {
+                println!("{}", err.to_string().red());
+                return false;
             },
-            Err(err) => println!("{}", err),
         }
     }
+    true
+}
+
+/// Syntax errors in input spread over several lines are
+/// reported by line and column rather than by position
+fn describe(err: &Error, input: &str) -> String{
+    match err {
+        Error::Syntax{message, position} if input.trim_end().contains('\n') => {
+            let before: Vec<char> = input.chars().take(*position).collect();
+            let line = before.iter().filter(|c| **c == '\n').count() + 1;
+            let column = before.iter().rev().take_while(|c| **c != '\n').count() + 1;
+            format!("{} (at line {}, column {})", message, line, column)
+        },
+        _ => err.to_string(),
+    }
 }
 
 fn print_title(){
@@ -62,8 +288,3 @@ fn print_title(){
     println!("{}",r"                                               / /-`---'-\ \".red());
     println!("{}",r"                                                /         \".red());
 }
-
-
-
-
-
@@ -0,0 +1,224 @@
+use std::str;
+
+use rustqlite::{Literal, Rows};
+
+use crate::csv;
+
+/// How result sets are printed, picked with the `.mode` command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Box,
+    Csv,
+    Tsv,
+    Json,
+    Jsonl,
+    Markdown,
+    Line,
+}
+
+impl Mode {
+    pub const NAMES: &'static str = "box, csv, tsv, json, jsonl, markdown, line";
+
+    pub fn from_name(name: &str) -> Option<Mode>{
+        match name.to_lowercase().as_str() {
+            "box" => Some(Mode::Box),
+            "csv" => Some(Mode::Csv),
+            "tsv" | "tabs" => Some(Mode::Tsv),
+            "json" => Some(Mode::Json),
+            "jsonl" => Some(Mode::Jsonl),
+            "markdown" => Some(Mode::Markdown),
+            "line" => Some(Mode::Line),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match self {
+            Mode::Box => "box",
+            Mode::Csv => "csv",
+            Mode::Tsv => "tsv",
+            Mode::Json => "json",
+            Mode::Jsonl => "jsonl",
+            Mode::Markdown => "markdown",
+            Mode::Line => "line",
+        }
+    }
+}
+
+/// The REPL's output settings. Headers can be switched off for
+/// the box, csv and tsv modes, the other modes always name columns
+pub struct Output {
+    pub mode: Mode,
+    pub headers: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self{
+        Output {
+            mode: Mode::Box,
+            headers: true,
+        }
+    }
+}
+
+impl Output {
+    /// Formats a result set, every line of the result ends with a newline
+    pub fn render(&self, table_name: &str, rows: &Rows) -> String{
+        match self.mode {
+            Mode::Box => self.boxed(table_name, rows),
+            Mode::Csv => self.separated(rows, ",", csv::field),
+            Mode::Tsv => self.separated(rows, "\t", tsv_field),
+            Mode::Json => json(rows),
+            Mode::Jsonl => jsonl(rows),
+            Mode::Markdown => markdown(rows),
+            Mode::Line => line(rows),
+        }
+    }
+
+    fn boxed(&self, table_name: &str, rows: &Rows) -> String{
+        let mut table_data: Vec<Vec<String>> = Vec::new();
+
+        if self.headers{
+            table_data.push(header(rows));
+        }
+        for row in rows{
+            table_data.push(row.iter().map(value).collect());
+        }
+
+        if table_data.is_empty(){
+            return format!("\n--{}--\n\n", table_name.to_uppercase());
+        }
+
+        let mut out = Vec::new();
+        text_tables::render(&mut out, table_data).unwrap();
+        format!("\n--{}--\n{}\n", table_name.to_uppercase(), str::from_utf8(&out).unwrap())
+    }
+
+    fn separated(&self, rows: &Rows, separator: &str, field: fn(&str) -> String) -> String{
+        let mut out = String::new();
+
+        if self.headers{
+            let header: Vec<String> = rows.columns.iter().map(|col| field(col)).collect();
+            out.push_str(&header.join(separator));
+            out.push('\n');
+        }
+
+        for row in rows{
+            let fields: Vec<String> = row.iter()
+                .map(|data| match data {
+                    Literal::Null => String::new(),
+                    _ => field(&value(data)),
+                })
+                .collect();
+            out.push_str(&fields.join(separator));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Column names followed by their declared type, as in `age (int)`
+fn header(rows: &Rows) -> Vec<String>{
+    rows.columns.iter()
+        .map(|column| match rows.column_type(column) {
+            Some(datatype) => format!("{} ({})", column, datatype),
+            None => column.clone(),
+        })
+        .collect()
+}
+
+fn value(data: &Literal) -> String{
+    match data {
+        Literal::Number(val) => val.to_string(),
+        Literal::String(val) => String::from(val),
+        Literal::Boolean(val) => val.to_string(),
+        Literal::Null => String::from("NULL"),
+        _ => String::from(""),
+    }
+}
+
+/// Tabs and line breaks would split the field, so they are escaped
+fn tsv_field(field: &str) -> String{
+    field.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn json(rows: &Rows) -> String{
+    let objects: Vec<String> = rows.iter()
+        .map(|row| json_object(&rows.columns, row))
+        .collect();
+
+    if objects.is_empty(){
+        return String::from("[]\n");
+    }
+    format!("[\n  {}\n]\n", objects.join(",\n  "))
+}
+
+fn jsonl(rows: &Rows) -> String{
+    rows.iter()
+        .map(|row| json_object(&rows.columns, row) + "\n")
+        .collect()
+}
+
+fn json_object(columns: &[String], row: &[Literal]) -> String{
+    let fields: Vec<String> = columns.iter().zip(row)
+        .map(|(column, data)| {
+            let val = match data {
+                Literal::Number(val) => val.to_string(),
+                Literal::Boolean(val) => val.to_string(),
+                Literal::String(val) => json_string(val),
+                _ => String::from("null"),
+            };
+            format!("{}: {}", json_string(column), val)
+        })
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_string(val: &str) -> String{
+    let mut out = String::from("\"");
+    for c in val.chars(){
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A markdown table always has a header row, otherwise it is not a table
+fn markdown(rows: &Rows) -> String{
+    let cell = |text: &str| text.replace('|', "\\|").replace('\n', "<br>");
+
+    let header: Vec<String> = rows.columns.iter().map(|col| cell(col)).collect();
+    let divider: Vec<&str> = rows.columns.iter().map(|_| "---").collect();
+
+    let mut out = format!("| {} |\n| {} |\n", header.join(" | "), divider.join(" | "));
+    for row in rows{
+        let cells: Vec<String> = row.iter().map(|data| cell(&value(data))).collect();
+        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+    out
+}
+
+/// One `column = value` line per column, with a blank line between rows
+fn line(rows: &Rows) -> String{
+    let width = rows.columns.iter().map(|col| col.chars().count()).max().unwrap_or(0);
+
+    let records: Vec<String> = rows.iter()
+        .map(|row| {
+            rows.columns.iter().zip(row)
+                .map(|(column, data)| format!("{:>width$} = {}\n", column, value(data), width = width))
+                .collect()
+        })
+        .collect();
+    records.join("\n")
+}
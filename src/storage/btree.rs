@@ -0,0 +1,643 @@
+use crate::error::Error;
+use crate::storage::pager::{Pager, PageNo, PAGE_SIZE, read_u16, write_u16, read_u32, write_u32};
+
+const LEAF: u8 = 1;
+const INTERIOR: u8 = 2;
+
+/// kind, cell count and the right pointer
+const HEADER_SIZE: usize = 7;
+
+/// Keys and inline values are capped so a full page can always be split
+/// into two halves that fit. Longer values go to a chain of overflow pages
+pub const MAX_KEY: usize = 512;
+const MAX_INLINE: usize = 512;
+
+const INLINE: u8 = 0;
+const OVERFLOW: u8 = 1;
+
+/// next page and the number of bytes used on this one
+const OVERFLOW_HEADER: usize = 6;
+
+#[derive(Debug, Clone)]
+enum Payload {
+    Inline(Vec<u8>),
+    Overflow{
+        len: u32,
+        page: PageNo,
+    },
+}
+
+/// A decoded page. Leaves hold the keys and values in order and link to
+/// the next leaf, interior cells point at the child holding keys smaller
+/// than the cell key and `right` holds everything else
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf{
+        cells: Vec<(Vec<u8>, Payload)>,
+        next: PageNo,
+    },
+    Interior{
+        cells: Vec<(Vec<u8>, PageNo)>,
+        right: PageNo,
+    },
+}
+
+impl Node {
+    fn read(pager: &mut Pager, page_no: PageNo) -> Result<Node, Error>{
+        let page = pager.read(page_no)?;
+        let corrupt = || Error::Corruption(format!("malformed B-tree page {}", page_no));
+
+        let count = read_u16(&page, 1) as usize;
+        let right = read_u32(&page, 3);
+        let mut pos = HEADER_SIZE;
+
+        let mut take = |len: usize| -> Result<&[u8], Error>{
+            if pos + len > PAGE_SIZE {
+                return Err(corrupt());
+            }
+            pos += len;
+            Ok(&page[pos - len..pos])
+        };
+
+        match page[0] {
+            LEAF => {
+                let mut cells = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_len = read_u16(take(2)?, 0) as usize;
+                    let key = take(key_len)?.to_vec();
+                    let payload = match take(1)?[0] {
+                        INLINE => {
+                            let len = read_u16(take(2)?, 0) as usize;
+                            Payload::Inline(take(len)?.to_vec())
+                        },
+                        OVERFLOW => Payload::Overflow{
+                            len: read_u32(take(4)?, 0),
+                            page: read_u32(take(4)?, 0),
+                        },
+                        _ => return Err(corrupt()),
+                    };
+                    cells.push((key, payload));
+                }
+                Ok(Node::Leaf{ cells, next: right })
+            },
+            INTERIOR => {
+                let mut cells = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_len = read_u16(take(2)?, 0) as usize;
+                    let key = take(key_len)?.to_vec();
+                    let child = read_u32(take(4)?, 0);
+                    cells.push((key, child));
+                }
+                Ok(Node::Interior{ cells, right })
+            },
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn write(&self, pager: &mut Pager, page_no: PageNo) -> Result<(), Error>{
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+
+        match self {
+            Node::Leaf{ cells, next } => {
+                page.push(LEAF);
+                page.extend_from_slice(&(cells.len() as u16).to_be_bytes());
+                page.extend_from_slice(&next.to_be_bytes());
+                for (key, payload) in cells {
+                    page.extend_from_slice(&(key.len() as u16).to_be_bytes());
+                    page.extend_from_slice(key);
+                    match payload {
+                        Payload::Inline(val) => {
+                            page.push(INLINE);
+                            page.extend_from_slice(&(val.len() as u16).to_be_bytes());
+                            page.extend_from_slice(val);
+                        },
+                        Payload::Overflow{ len, page: first } => {
+                            page.push(OVERFLOW);
+                            page.extend_from_slice(&len.to_be_bytes());
+                            page.extend_from_slice(&first.to_be_bytes());
+                        },
+                    }
+                }
+            },
+            Node::Interior{ cells, right } => {
+                page.push(INTERIOR);
+                page.extend_from_slice(&(cells.len() as u16).to_be_bytes());
+                page.extend_from_slice(&right.to_be_bytes());
+                for (key, child) in cells {
+                    page.extend_from_slice(&(key.len() as u16).to_be_bytes());
+                    page.extend_from_slice(key);
+                    page.extend_from_slice(&child.to_be_bytes());
+                }
+            },
+        }
+
+        debug_assert!(page.len() <= PAGE_SIZE);
+        page.resize(PAGE_SIZE, 0);
+        pager.write(page_no, page)
+    }
+
+    fn size(&self) -> usize{
+        match self {
+            Node::Leaf{ cells, .. } => HEADER_SIZE + cells.iter().map(leaf_cell_size).sum::<usize>(),
+            Node::Interior{ cells, .. } => HEADER_SIZE + cells.iter().map(interior_cell_size).sum::<usize>(),
+        }
+    }
+}
+
+fn leaf_cell_size(cell: &(Vec<u8>, Payload)) -> usize{
+    match &cell.1 {
+        Payload::Inline(val) => 2 + cell.0.len() + 1 + 2 + val.len(),
+        Payload::Overflow{..} => 2 + cell.0.len() + 1 + 8,
+    }
+}
+
+fn interior_cell_size(cell: &(Vec<u8>, PageNo)) -> usize{
+    2 + cell.0.len() + 4
+}
+
+/// Index at which the cells are split so both halves take up
+/// about the same number of bytes, neither half is left empty
+fn split_point<T>(cells: &[T], size: impl Fn(&T) -> usize) -> usize{
+    let total: usize = cells.iter().map(&size).sum();
+    let mut used = 0;
+
+    for (idx, cell) in cells.iter().enumerate() {
+        used += size(cell);
+        if used >= total / 2 {
+            return (idx + 1).clamp(1, cells.len() - 1);
+        }
+    }
+    cells.len() / 2
+}
+
+/// Allocates an empty tree and returns its root page, which stays
+/// the same for the lifetime of the tree
+pub fn create(pager: &mut Pager) -> Result<PageNo, Error>{
+    let root = pager.allocate()?;
+    Node::Leaf{ cells: Vec::new(), next: 0 }.write(pager, root)?;
+    Ok(root)
+}
+
+/// Looks up the value stored under `key`
+pub fn get(pager: &mut Pager, root: PageNo, key: &[u8]) -> Result<Option<Vec<u8>>, Error>{
+    let mut page_no = root;
+
+    loop {
+        match Node::read(pager, page_no)? {
+            Node::Interior{ cells, right } => page_no = child_for(&cells, right, key).1,
+            Node::Leaf{ cells, .. } => {
+                return match cells.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(idx) => Ok(Some(load(pager, &cells[idx].1)?)),
+                    Err(_) => Ok(None),
+                };
+            },
+        }
+    }
+}
+
+/// Stores `value` under `key`, replacing the previous value if there was one
+pub fn insert(pager: &mut Pager, root: PageNo, key: &[u8], value: &[u8]) -> Result<(), Error>{
+    if key.len() > MAX_KEY {
+        return Err(Error::Execution(format!("Key is too large, the limit is {} bytes", MAX_KEY)));
+    }
+
+    let payload = store(pager, value)?;
+
+    if let Some((separator, new_page)) = insert_into(pager, root, key, payload)? {
+        // The root has to stay where it is, so its left half moves to a new
+        // page and the root becomes an interior node over both halves
+        let left = pager.allocate()?;
+        let node = Node::read(pager, root)?;
+        node.write(pager, left)?;
+        Node::Interior{
+            cells: vec![(separator, left)],
+            right: new_page,
+        }.write(pager, root)?;
+    }
+    Ok(())
+}
+
+/// Removes `key`, returns false when it was not there. Pages are not
+/// merged, an emptied leaf stays in the tree until it is filled again
+pub fn delete(pager: &mut Pager, root: PageNo, key: &[u8]) -> Result<bool, Error>{
+    let mut page_no = root;
+
+    loop {
+        match Node::read(pager, page_no)? {
+            Node::Interior{ cells, right } => page_no = child_for(&cells, right, key).1,
+            Node::Leaf{ mut cells, next } => {
+                let idx = match cells.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(idx) => idx,
+                    Err(_) => return Ok(false),
+                };
+                let (_, payload) = cells.remove(idx);
+                release(pager, &payload)?;
+                Node::Leaf{ cells, next }.write(pager, page_no)?;
+                return Ok(true);
+            },
+        }
+    }
+}
+
+/// The largest key in the tree. The rightmost leaf is tried first, as pages
+/// are not merged it may have been emptied by deletes and the leaves to the
+/// left of it are searched in turn
+pub fn last(pager: &mut Pager, root: PageNo) -> Result<Option<Vec<u8>>, Error>{
+    match Node::read(pager, root)? {
+        Node::Leaf{ cells, .. } => Ok(cells.last().map(|(key, _)| key.clone())),
+        Node::Interior{ cells, right } => {
+            let children = std::iter::once(right).chain(cells.iter().rev().map(|(_, child)| *child));
+            for child in children {
+                if let Some(key) = last(pager, child)? {
+                    return Ok(Some(key));
+                }
+            }
+            Ok(None)
+        },
+    }
+}
+
+/// Frees every page of the tree, including the root
+pub fn destroy(pager: &mut Pager, root: PageNo) -> Result<(), Error>{
+    match Node::read(pager, root)? {
+        Node::Interior{ cells, right } => {
+            for (_, child) in cells {
+                destroy(pager, child)?;
+            }
+            destroy(pager, right)?;
+        },
+        Node::Leaf{ cells, .. } => {
+            for (_, payload) in cells {
+                release(pager, &payload)?;
+            }
+        },
+    }
+    pager.free(root)
+}
+
+/// A key along with the value stored under it
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Walks the leaves of a tree in key order
+pub struct Cursor {
+    cells: Vec<(Vec<u8>, Payload)>,
+    index: usize,
+    next: PageNo,
+}
+
+impl Cursor {
+    /// Positioned before the smallest key
+    pub fn first(pager: &mut Pager, root: PageNo) -> Result<Cursor, Error>{
+        Cursor::seek(pager, root, &[])
+    }
+
+    /// Positioned before the smallest key that is not less than `key`
+    pub fn seek(pager: &mut Pager, root: PageNo, key: &[u8]) -> Result<Cursor, Error>{
+        let mut page_no = root;
+
+        loop {
+            match Node::read(pager, page_no)? {
+                Node::Interior{ cells, right } => page_no = child_for(&cells, right, key).1,
+                Node::Leaf{ cells, next } => {
+                    let index = cells.partition_point(|(k, _)| k.as_slice() < key);
+                    return Ok(Cursor{ cells, index, next });
+                },
+            }
+        }
+    }
+
+    pub fn next(&mut self, pager: &mut Pager) -> Result<Option<Entry>, Error>{
+        while self.index >= self.cells.len() {
+            if self.next == 0 {
+                return Ok(None);
+            }
+            match Node::read(pager, self.next)? {
+                Node::Leaf{ cells, next } => {
+                    self.cells = cells;
+                    self.next = next;
+                    self.index = 0;
+                },
+                Node::Interior{..} => return Err(Error::Corruption(String::from("leaf links to an interior page"))),
+            }
+        }
+
+        let (key, payload) = &self.cells[self.index];
+        self.index += 1;
+        Ok(Some((key.clone(), load(pager, payload)?)))
+    }
+}
+
+/// Position and page of the child that may hold `key`
+fn child_for(cells: &[(Vec<u8>, PageNo)], right: PageNo, key: &[u8]) -> (usize, PageNo){
+    let idx = cells.partition_point(|(k, _)| k.as_slice() <= key);
+    match cells.get(idx) {
+        Some((_, child)) => (idx, *child),
+        None => (idx, right),
+    }
+}
+
+/// Inserts below `page_no`. When the page had to be split, the upper half
+/// now lives on a new page and its first key is handed back to the parent
+fn insert_into(pager: &mut Pager, page_no: PageNo, key: &[u8], payload: Payload) -> Result<Option<(Vec<u8>, PageNo)>, Error>{
+    match Node::read(pager, page_no)? {
+        Node::Leaf{ mut cells, next } => {
+            match cells.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                Ok(idx) => {
+                    let old = std::mem::replace(&mut cells[idx].1, payload);
+                    release(pager, &old)?;
+                },
+                Err(idx) => cells.insert(idx, (key.to_vec(), payload)),
+            }
+
+            let node = Node::Leaf{ cells, next };
+            if node.size() <= PAGE_SIZE {
+                node.write(pager, page_no)?;
+                return Ok(None);
+            }
+
+            let Node::Leaf{ mut cells, next } = node else { unreachable!() };
+            let mid = split_point(&cells, leaf_cell_size);
+            let upper = cells.split_off(mid);
+            let separator = upper[0].0.clone();
+
+            let new_page = pager.allocate()?;
+            Node::Leaf{ cells: upper, next }.write(pager, new_page)?;
+            Node::Leaf{ cells, next: new_page }.write(pager, page_no)?;
+            Ok(Some((separator, new_page)))
+        },
+        Node::Interior{ mut cells, mut right } => {
+            let (idx, child) = child_for(&cells, right, key);
+
+            let (separator, new_page) = match insert_into(pager, child, key, payload)? {
+                Some(split) => split,
+                None => return Ok(None),
+            };
+
+            // The child keeps the lower half, so it moves in front of the
+            // separator and the new page takes over its old slot
+            cells.insert(idx, (separator, child));
+            match cells.get_mut(idx + 1) {
+                Some(cell) => cell.1 = new_page,
+                None => right = new_page,
+            }
+
+            let node = Node::Interior{ cells, right };
+            if node.size() <= PAGE_SIZE {
+                node.write(pager, page_no)?;
+                return Ok(None);
+            }
+
+            let Node::Interior{ mut cells, right } = node else { unreachable!() };
+            let mid = split_point(&cells, interior_cell_size);
+            let upper = cells.split_off(mid + 1);
+            let (promoted, left_right) = cells.pop().unwrap();
+
+            let new_page = pager.allocate()?;
+            Node::Interior{ cells: upper, right }.write(pager, new_page)?;
+            Node::Interior{ cells, right: left_right }.write(pager, page_no)?;
+            Ok(Some((promoted, new_page)))
+        },
+    }
+}
+
+/// Keeps small values inline and moves large ones to overflow pages
+fn store(pager: &mut Pager, value: &[u8]) -> Result<Payload, Error>{
+    if value.len() <= MAX_INLINE {
+        return Ok(Payload::Inline(value.to_vec()));
+    }
+
+    let chunk = PAGE_SIZE - OVERFLOW_HEADER;
+    let chunks: Vec<&[u8]> = value.chunks(chunk).collect();
+    let mut next = 0;
+
+    // Written back to front so every page already knows its successor
+    for data in chunks.iter().rev() {
+        let page_no = pager.allocate()?;
+        let mut page = vec![0u8; PAGE_SIZE];
+        write_u32(&mut page, 0, next);
+        write_u16(&mut page, 4, data.len() as u16);
+        page[OVERFLOW_HEADER..OVERFLOW_HEADER + data.len()].copy_from_slice(data);
+        pager.write(page_no, page)?;
+        next = page_no;
+    }
+
+    Ok(Payload::Overflow{
+        len: value.len() as u32,
+        page: next,
+    })
+}
+
+fn load(pager: &mut Pager, payload: &Payload) -> Result<Vec<u8>, Error>{
+    match payload {
+        Payload::Inline(val) => Ok(val.clone()),
+        Payload::Overflow{ len, page } => {
+            let mut value = Vec::with_capacity(*len as usize);
+            let mut page_no = *page;
+
+            while page_no != 0 && value.len() < *len as usize {
+                let page = pager.read(page_no)?;
+                let used = (read_u16(&page, 4) as usize).min(PAGE_SIZE - OVERFLOW_HEADER);
+                value.extend_from_slice(&page[OVERFLOW_HEADER..OVERFLOW_HEADER + used]);
+                page_no = read_u32(&page, 0);
+            }
+
+            if value.len() != *len as usize {
+                return Err(Error::Corruption(String::from("overflow chain is truncated")));
+            }
+            Ok(value)
+        },
+    }
+}
+
+fn release(pager: &mut Pager, payload: &Payload) -> Result<(), Error>{
+    if let Payload::Overflow{ page, .. } = payload {
+        let mut page_no = *page;
+        while page_no != 0 {
+            let next = read_u32(&pager.read(page_no)?, 0);
+            pager.free(page_no)?;
+            page_no = next;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys long enough that a page holds only a dozen of them, so a few
+    /// thousand build a tree with interior pages below the root
+    fn key(n: u32) -> Vec<u8>{
+        format!("{:08}{}", n, "k".repeat(300)).into_bytes()
+    }
+
+    fn value(n: u32) -> Vec<u8>{
+        format!("value {}", n).into_bytes()
+    }
+
+    /// Every number below `count` once, in a scrambled order
+    fn scrambled(count: u32) -> Vec<u32>{
+        (0..count).map(|n| (n * 7919) % count).collect()
+    }
+
+    fn keys(pager: &mut Pager, root: PageNo) -> Result<Vec<Vec<u8>>, Error>{
+        let mut cursor = Cursor::first(pager, root)?;
+        let mut keys = Vec::new();
+        while let Some((key, _)) = cursor.next(pager)? {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn depth(pager: &mut Pager, root: PageNo) -> Result<usize, Error>{
+        match Node::read(pager, root)? {
+            Node::Leaf{..} => Ok(1),
+            Node::Interior{ right, .. } => Ok(1 + depth(pager, right)?),
+        }
+    }
+
+    #[test]
+    fn splits_leaves_and_interior_pages() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+        let count = 3000;
+
+        for n in scrambled(count) {
+            insert(&mut pager, root, &key(n), &value(n))?;
+        }
+
+        assert!(depth(&mut pager, root)? >= 3);
+        assert_eq!(keys(&mut pager, root)?, (0..count).map(key).collect::<Vec<_>>());
+        for n in 0..count {
+            assert_eq!(get(&mut pager, root, &key(n))?, Some(value(n)));
+        }
+        assert_eq!(last(&mut pager, root)?, Some(key(count - 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_the_value_of_an_existing_key() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+
+        insert(&mut pager, root, b"a", b"first")?;
+        insert(&mut pager, root, b"a", b"second")?;
+
+        assert_eq!(get(&mut pager, root, b"a")?, Some(b"second".to_vec()));
+        assert_eq!(keys(&mut pager, root)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_keep_the_rest_in_order() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+        let count = 2000;
+
+        for n in scrambled(count) {
+            insert(&mut pager, root, &key(n), &value(n))?;
+        }
+        for n in scrambled(count).into_iter().filter(|n| n % 3 != 0) {
+            assert!(delete(&mut pager, root, &key(n))?);
+        }
+        assert!(!delete(&mut pager, root, &key(1))?);
+
+        let expected: Vec<Vec<u8>> = (0..count).filter(|n| n % 3 == 0).map(key).collect();
+        assert_eq!(keys(&mut pager, root)?, expected);
+        assert_eq!(get(&mut pager, root, &key(2))?, None);
+        assert_eq!(get(&mut pager, root, &key(3))?, Some(value(3)));
+
+        // the rightmost leaves are empty now, the largest key is further left
+        for n in (count / 2..count).filter(|n| n % 3 == 0) {
+            delete(&mut pager, root, &key(n))?;
+        }
+        assert_eq!(last(&mut pager, root)?, Some(key(999)));
+
+        for n in 0..count {
+            delete(&mut pager, root, &key(n))?;
+        }
+        assert!(keys(&mut pager, root)?.is_empty());
+        assert_eq!(last(&mut pager, root)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn seeks_to_the_first_key_not_less_than_the_target() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+
+        for n in scrambled(500).into_iter().filter(|n| n % 2 == 0) {
+            insert(&mut pager, root, &key(n), &value(n))?;
+        }
+
+        let mut cursor = Cursor::seek(&mut pager, root, &key(301))?;
+        assert_eq!(cursor.next(&mut pager)?.map(|(key, _)| key), Some(key(302)));
+        assert_eq!(cursor.next(&mut pager)?.map(|(key, _)| key), Some(key(304)));
+
+        let mut cursor = Cursor::seek(&mut pager, root, &key(499))?;
+        assert_eq!(cursor.next(&mut pager)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn large_values_round_trip_through_overflow_pages() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+        let large: Vec<u8> = (0..PAGE_SIZE * 3 + 17).map(|n| (n % 251) as u8).collect();
+
+        insert(&mut pager, root, b"large", &large)?;
+        insert(&mut pager, root, b"small", b"inline")?;
+
+        assert_eq!(get(&mut pager, root, b"large")?, Some(large.clone()));
+        assert_eq!(get(&mut pager, root, b"small")?, Some(b"inline".to_vec()));
+
+        let mut cursor = Cursor::first(&mut pager, root)?;
+        assert_eq!(cursor.next(&mut pager)?, Some((b"large".to_vec(), large)));
+        Ok(())
+    }
+
+    #[test]
+    fn freed_pages_are_reused() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+        let large = vec![7u8; PAGE_SIZE * 4];
+
+        // the new chain of a replaced value is written before the old one
+        // is freed, from then on each replacement reuses the chain before it
+        insert(&mut pager, root, b"large", &large)?;
+        insert(&mut pager, root, b"large", &large)?;
+        let pages = pager.page_count();
+        insert(&mut pager, root, b"large", &large)?;
+        assert_eq!(pager.page_count(), pages);
+
+        delete(&mut pager, root, b"large")?;
+        insert(&mut pager, root, b"other", &large)?;
+        assert_eq!(pager.page_count(), pages);
+
+        // as does dropping a whole tree
+        let tree = create(&mut pager)?;
+        for n in 0..200 {
+            insert(&mut pager, tree, &key(n), &value(n))?;
+        }
+        let pages = pager.page_count();
+        destroy(&mut pager, tree)?;
+
+        let tree = create(&mut pager)?;
+        for n in 0..200 {
+            insert(&mut pager, tree, &key(n), &value(n))?;
+        }
+        assert_eq!(pager.page_count(), pages);
+        assert_eq!(get(&mut pager, root, b"other")?, Some(large));
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_keys_over_the_limit() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let root = create(&mut pager)?;
+
+        assert!(insert(&mut pager, root, &vec![0u8; MAX_KEY + 1], b"").is_err());
+        insert(&mut pager, root, &vec![0u8; MAX_KEY], b"")?;
+        Ok(())
+    }
+}
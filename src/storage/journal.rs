@@ -0,0 +1,288 @@
+//! The rollback journal lives next to the database file. Before a commit
+//! overwrites any page, the original contents of those pages are saved here.
+//! If the process dies part way through the commit, the next open finds the
+//! journal and copies the original pages back, so the database is left as it
+//! was before the commit started
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::storage::pager::{PageNo, PAGE_SIZE, read_u32, write_u32};
+
+const MAGIC: &[u8; 8] = b"RQLJRNL\0";
+
+/// magic, page count before the commit, number of pages and checksum
+const HEADER_SIZE: usize = 24;
+
+/// The contents of a complete journal
+struct Saved<'a> {
+    page_count: u32,
+    pages: Vec<(PageNo, &'a [u8])>,
+}
+
+/// `data/database.rdb` is journaled to `data/database.rdb-journal`
+pub fn path_for(db_path: &Path) -> PathBuf{
+    let mut name = db_path.as_os_str().to_owned();
+    name.push("-journal");
+    PathBuf::from(name)
+}
+
+/// Saves the original pages and makes sure they are on disk
+/// before the caller starts overwriting the database
+pub fn write(path: &Path, page_count: u32, pages: &[(PageNo, Vec<u8>)]) -> Result<(), Error>{
+    let mut body = Vec::with_capacity(pages.len() * (4 + PAGE_SIZE));
+    for (page_no, data) in pages {
+        body.extend_from_slice(&page_no.to_be_bytes());
+        body.extend_from_slice(data);
+    }
+
+    let mut header = vec![0u8; HEADER_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    write_u32(&mut header, 8, page_count);
+    write_u32(&mut header, 12, pages.len() as u32);
+    header[16..24].copy_from_slice(&checksum(&body).to_be_bytes());
+
+    let mut file = File::create(path)?;
+    file.write_all(&header)?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    sync_parent(path);
+    Ok(())
+}
+
+/// Called once the database itself has been synced. Truncating first means
+/// a journal that survives a failed delete no longer counts as hot
+pub fn clear(path: &Path) -> Result<(), Error>{
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(0)?;
+    file.sync_all()?;
+
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Rolls back a commit that was interrupted. A journal that was not
+/// completely written means the database was never touched, so it is
+/// simply thrown away
+pub fn recover(path: &Path, db_file: &mut File) -> Result<(), Error>{
+    let mut journal = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut contents = Vec::new();
+    journal.read_to_end(&mut contents)?;
+
+    if let Some(saved) = parse(&contents) {
+        for (page_no, data) in saved.pages {
+            db_file.seek(SeekFrom::Start(page_no as u64 * PAGE_SIZE as u64))?;
+            db_file.write_all(data)?;
+        }
+        db_file.set_len(saved.page_count as u64 * PAGE_SIZE as u64)?;
+        db_file.sync_all()?;
+    }
+
+    clear(path)
+}
+
+/// None unless the journal is complete and its checksum matches
+fn parse(contents: &[u8]) -> Option<Saved<'_>>{
+    if contents.len() < HEADER_SIZE || &contents[0..8] != MAGIC {
+        return None;
+    }
+
+    let page_count = read_u32(contents, 8);
+    let count = read_u32(contents, 12) as usize;
+    let expected = u64::from_be_bytes(contents[16..24].try_into().ok()?);
+    let body = &contents[HEADER_SIZE..];
+
+    if body.len() != count * (4 + PAGE_SIZE) || checksum(body) != expected {
+        return None;
+    }
+
+    let pages = body.chunks(4 + PAGE_SIZE)
+        .map(|entry| (read_u32(entry, 0), &entry[4..]))
+        .collect();
+    Some(Saved{
+        page_count,
+        pages,
+    })
+}
+
+/// FNV-1a, enough to tell a torn journal from a complete one
+fn checksum(bytes: &[u8]) -> u64{
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Makes the new journal's directory entry durable. Not every
+/// platform lets a directory be opened, so failures are ignored
+fn sync_parent(path: &Path){
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pager::Pager;
+
+    fn temp_path(name: &str) -> PathBuf{
+        let path = std::env::temp_dir().join(format!("rustqlite-journal-{}-{}.rdb", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path_for(&path));
+        path
+    }
+
+    fn page(fill: u8) -> Vec<u8>{
+        vec![fill; PAGE_SIZE]
+    }
+
+    /// A committed database of three pages filled with 1, 2 and 3
+    fn database(path: &Path) -> Result<(), Error>{
+        let mut pager = Pager::open(path)?;
+        for fill in 1..=3 {
+            let page_no = pager.allocate()?;
+            pager.write(page_no, page(fill))?;
+        }
+        pager.commit()
+    }
+
+    fn raw_page(path: &Path, page_no: PageNo) -> Result<Vec<u8>, Error>{
+        let contents = fs::read(path)?;
+        let start = page_no as usize * PAGE_SIZE;
+        Ok(contents[start..start + PAGE_SIZE].to_vec())
+    }
+
+    /// Does what a commit does up to the point it is interrupted: the
+    /// original pages are journaled, then page 2 is overwritten and the
+    /// file grows by a page
+    fn interrupted_commit(path: &Path) -> Result<(), Error>{
+        write(&path_for(path), 4, &[(0, raw_page(path, 0)?), (2, raw_page(path, 2)?)])?;
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(2 * PAGE_SIZE as u64))?;
+        file.write_all(&page(9))?;
+        file.seek(SeekFrom::Start(4 * PAGE_SIZE as u64))?;
+        file.write_all(&page(9))?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn assert_original(path: &Path) -> Result<(), Error>{
+        assert!(!path_for(path).exists());
+        assert_eq!(fs::metadata(path)?.len(), 4 * PAGE_SIZE as u64);
+
+        let mut pager = Pager::open(path)?;
+        for fill in 1..=3 {
+            assert_eq!(pager.read(fill as PageNo)?, page(fill));
+        }
+        assert!(pager.read(4).is_err());
+        Ok(())
+    }
+
+    fn cleanup(path: &Path){
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(path_for(path));
+    }
+
+    #[test]
+    fn commit_leaves_no_journal_behind() -> Result<(), Error>{
+        let path = temp_path("commit");
+        database(&path)?;
+        assert!(!path_for(&path).exists());
+
+        // a commit that overwrites pages journals them first
+        let mut pager = Pager::open(&path)?;
+        pager.write(2, page(8))?;
+        pager.commit()?;
+        assert!(!path_for(&path).exists());
+        assert_eq!(raw_page(&path, 2)?, page(8));
+        cleanup(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn hot_journal_is_rolled_back_on_open() -> Result<(), Error>{
+        let path = temp_path("hot");
+        database(&path)?;
+        interrupted_commit(&path)?;
+        assert_eq!(raw_page(&path, 2)?, page(9));
+
+        Pager::open(&path)?;
+        assert_original(&path)?;
+        cleanup(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_journal_is_thrown_away() -> Result<(), Error>{
+        let path = temp_path("torn");
+        database(&path)?;
+
+        // the journal was still being written, the database is untouched
+        write(&path_for(&path), 4, &[(0, raw_page(&path, 0)?), (2, raw_page(&path, 2)?)])?;
+        let journal = OpenOptions::new().write(true).open(path_for(&path))?;
+        journal.set_len((HEADER_SIZE + 4 + PAGE_SIZE + 100) as u64)?;
+        drop(journal);
+
+        Pager::open(&path)?;
+        assert_original(&path)?;
+        cleanup(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn journal_with_a_bad_checksum_is_thrown_away() -> Result<(), Error>{
+        let path = temp_path("checksum");
+        database(&path)?;
+
+        // a journaled page that does not match its checksum is never copied back
+        write(&path_for(&path), 4, &[(2, page(7))])?;
+        let mut contents = fs::read(path_for(&path))?;
+        contents[HEADER_SIZE + 10] ^= 0xff;
+        fs::write(path_for(&path), contents)?;
+
+        Pager::open(&path)?;
+        assert_original(&path)?;
+        cleanup(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn short_or_empty_journal_is_thrown_away() -> Result<(), Error>{
+        let path = temp_path("short");
+        database(&path)?;
+
+        for contents in [&b""[..], &MAGIC[..], &b"not a journal at all, just some bytes"[..]] {
+            fs::write(path_for(&path), contents)?;
+            Pager::open(&path)?;
+            assert_original(&path)?;
+        }
+        cleanup(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_tells_torn_bodies_apart(){
+        let body = page(5);
+        let mut torn = body.clone();
+        torn[PAGE_SIZE - 1] = 0;
+
+        assert_eq!(checksum(&body), checksum(&page(5)));
+        assert_ne!(checksum(&body), checksum(&torn));
+        assert_ne!(checksum(&body), checksum(&body[..PAGE_SIZE - 1]));
+    }
+}
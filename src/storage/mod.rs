@@ -0,0 +1,7 @@
+//! The storage engine underneath the VM. The pager moves fixed size pages
+//! between the database file and a page cache, and B-trees built on top of
+//! those pages hold the catalog and the rows of every table. Commits
+//! are made atomic by the rollback journal
+pub mod pager;
+pub mod journal;
+pub mod btree;
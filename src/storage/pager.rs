@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::storage::journal;
+
+/// Every page in a database file has the same size, page 0 holds the
+/// file header and the remaining pages belong to B-trees or the freelist
+pub const PAGE_SIZE: usize = 4096;
+
+/// Clean pages beyond this count are evicted from the cache,
+/// least recently used first. Dirty pages are kept until commit
+const CACHE_PAGES: usize = 512;
+
+const MAGIC: &[u8; 16] = b"RustQLite pages\0";
+
+pub type PageNo = u32;
+
+#[derive(Clone)]
+struct CachedPage {
+    data: Vec<u8>,
+    dirty: bool,
+    used: u64,
+}
+
+/// The state of the pager when a savepoint was taken. Each page written
+/// since then has its previous cache entry saved the first time it is
+/// written, `None` when it was not cached and so still matches the file
+struct Savepoint {
+    page_count: u32,
+    freelist: PageNo,
+    saved: HashMap<PageNo, Option<CachedPage>>,
+}
+
+enum Backend {
+    File{
+        file: File,
+        journal: PathBuf,
+    },
+    Memory(Vec<Vec<u8>>),
+}
+
+/// Reads and writes fixed size pages, keeping recently used ones in a
+/// cache. Changes stay in the cache until `commit` writes the dirty
+/// pages out, or `rollback` throws them away. Commits to a file go
+/// through the rollback journal so they either happen completely or not at all
+pub struct Pager {
+    backend: Backend,
+    cache: HashMap<PageNo, CachedPage>,
+    page_count: u32,
+    freelist: PageNo,
+    committed_count: u32,
+    committed_freelist: PageNo,
+    savepoints: Vec<Savepoint>,
+    tick: u64,
+}
+
+impl Pager {
+    /// Opens a database file, a new or empty file gets a fresh header.
+    /// A journal left behind by an interrupted commit is rolled back first
+    pub fn open(path: &Path) -> Result<Pager, Error>{
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let journal = journal::path_for(path);
+        journal::recover(&journal, &mut file)?;
+
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Ok(Pager::fresh(Backend::File{ file, journal }));
+        }
+
+        if len % PAGE_SIZE as u64 != 0 {
+            return Err(Error::Corruption(String::from("file size is not a whole number of pages")));
+        }
+
+        let mut header = vec![0u8; PAGE_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+
+        if &header[0..16] != MAGIC {
+            return Err(Error::Corruption(String::from("file is not a Rustqlite database")));
+        }
+        if read_u32(&header, 16) as usize != PAGE_SIZE {
+            return Err(Error::Corruption(String::from("unsupported page size")));
+        }
+
+        let page_count = read_u32(&header, 20);
+        let freelist = read_u32(&header, 24);
+
+        if page_count as u64 * PAGE_SIZE as u64 > len {
+            return Err(Error::Corruption(String::from("file is shorter than its header claims")));
+        }
+
+        Ok(Pager{
+            backend: Backend::File{ file, journal },
+            cache: HashMap::new(),
+            page_count,
+            freelist,
+            committed_count: page_count,
+            committed_freelist: freelist,
+            savepoints: Vec::new(),
+            tick: 0,
+        })
+    }
+
+    /// A pager that never touches the disk
+    pub fn memory() -> Pager{
+        Pager::fresh(Backend::Memory(Vec::new()))
+    }
+
+    /// Only the header page exists, it is written on the first commit
+    fn fresh(backend: Backend) -> Pager{
+        Pager{
+            backend,
+            cache: HashMap::new(),
+            page_count: 1,
+            freelist: 0,
+            committed_count: 0,
+            committed_freelist: 0,
+            savepoints: Vec::new(),
+            tick: 0,
+        }
+    }
+
+    /// True until the header has been written out for the first time
+    pub fn is_new(&self) -> bool{
+        self.committed_count == 0
+    }
+
+    /// Number of pages in the database, the header and free pages included
+    #[cfg(test)]
+    pub fn page_count(&self) -> u32{
+        self.page_count
+    }
+
+    /// Returns a copy of the page, callers hand it back through `write`
+    pub fn read(&mut self, page_no: PageNo) -> Result<Vec<u8>, Error>{
+        if page_no == 0 || page_no >= self.page_count {
+            return Err(Error::Corruption(format!("page {} is out of range", page_no)));
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(page) = self.cache.get_mut(&page_no) {
+            page.used = tick;
+            return Ok(page.data.clone());
+        }
+
+        let data = self.read_raw(page_no)?;
+        self.evict();
+        self.cache.insert(page_no, CachedPage{
+            data: data.clone(),
+            dirty: false,
+            used: tick,
+        });
+        Ok(data)
+    }
+
+    pub fn write(&mut self, page_no: PageNo, data: Vec<u8>) -> Result<(), Error>{
+        if page_no == 0 || page_no >= self.page_count {
+            return Err(Error::Corruption(format!("page {} is out of range", page_no)));
+        }
+        debug_assert_eq!(data.len(), PAGE_SIZE);
+
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.saved.entry(page_no)
+                .or_insert_with(|| self.cache.get(&page_no).cloned());
+        }
+
+        self.tick += 1;
+        self.cache.insert(page_no, CachedPage{
+            data,
+            dirty: true,
+            used: self.tick,
+        });
+        Ok(())
+    }
+
+    /// Hands out a zeroed page, reusing one from the freelist if possible
+    pub fn allocate(&mut self) -> Result<PageNo, Error>{
+        if self.freelist != 0 {
+            let page_no = self.freelist;
+            let page = self.read(page_no)?;
+            self.freelist = read_u32(&page, 0);
+            self.write(page_no, vec![0u8; PAGE_SIZE])?;
+            return Ok(page_no);
+        }
+
+        let page_no = self.page_count;
+        self.page_count += 1;
+        self.write(page_no, vec![0u8; PAGE_SIZE])?;
+        Ok(page_no)
+    }
+
+    /// Puts a page on the freelist so a later allocation can reuse it
+    pub fn free(&mut self, page_no: PageNo) -> Result<(), Error>{
+        let mut page = vec![0u8; PAGE_SIZE];
+        write_u32(&mut page, 0, self.freelist);
+        self.write(page_no, page)?;
+        self.freelist = page_no;
+        Ok(())
+    }
+
+    /// True when there are changes that have not been committed
+    pub fn is_dirty(&self) -> bool{
+        self.page_count != self.committed_count
+            || self.freelist != self.committed_freelist
+            || self.cache.values().any(|page| page.dirty)
+    }
+
+    /// Writes every dirty page and the header back to the file. The pages
+    /// being overwritten are saved to the journal first, and the journal
+    /// is only cleared once the new pages are safely on disk
+    pub fn commit(&mut self) -> Result<(), Error>{
+        if !self.is_dirty() {
+            return Ok(());
+        }
+
+        let mut dirty: Vec<PageNo> = self.cache.iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(page_no, _)| *page_no)
+            .collect();
+        dirty.sort_unstable();
+
+        let journal = match &self.backend {
+            Backend::File{ journal, .. } => Some(journal.clone()),
+            Backend::Memory(_) => None,
+        };
+
+        if let Some(journal) = &journal {
+            // pages past the old end of the file are dropped by truncating it
+            let mut originals = Vec::new();
+            for page_no in std::iter::once(0).chain(dirty.iter().copied()) {
+                if page_no < self.committed_count {
+                    originals.push((page_no, self.read_raw(page_no)?));
+                }
+            }
+            journal::write(journal, self.committed_count, &originals)?;
+        }
+
+        if let Err(err) = self.write_pages(&dirty) {
+            // put back what was already overwritten, so the file and
+            // the clean pages in the cache still agree after a rollback
+            if let (Some(journal), Backend::File{ file, .. }) = (&journal, &mut self.backend) {
+                let _ = journal::recover(journal, file);
+            }
+            return Err(err);
+        }
+
+        for page_no in &dirty {
+            if let Some(page) = self.cache.get_mut(page_no) {
+                page.dirty = false;
+            }
+        }
+        self.committed_count = self.page_count;
+        self.committed_freelist = self.freelist;
+        self.evict();
+
+        self.savepoints.clear();
+
+        if let Some(journal) = &journal {
+            journal::clear(journal)?;
+        }
+        Ok(())
+    }
+
+    fn write_pages(&mut self, dirty: &[PageNo]) -> Result<(), Error>{
+        for page_no in dirty {
+            let data = self.cache[page_no].data.clone();
+            self.write_raw(*page_no, &data)?;
+        }
+
+        let header = self.header();
+        self.write_raw(0, &header)?;
+
+        if let Backend::File{ file, .. } = &mut self.backend {
+            file.set_len(self.page_count as u64 * PAGE_SIZE as u64)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Throws away every change made since the last commit
+    pub fn rollback(&mut self){
+        self.savepoints.clear();
+        self.cache.retain(|_, page| !page.dirty);
+        self.page_count = self.committed_count.max(1);
+        self.freelist = self.committed_freelist;
+    }
+
+    /// Marks the current state so later changes can be undone on their
+    /// own with `rollback_savepoint`. Savepoints nest
+    pub fn begin_savepoint(&mut self){
+        self.savepoints.push(Savepoint{
+            page_count: self.page_count,
+            freelist: self.freelist,
+            saved: HashMap::new(),
+        });
+    }
+
+    /// Keeps the changes made since the newest savepoint. They become part
+    /// of the savepoint below it, or of the next commit if there is none
+    pub fn release_savepoint(&mut self){
+        let released = match self.savepoints.pop() {
+            Some(savepoint) => savepoint,
+            None => return,
+        };
+
+        if let Some(below) = self.savepoints.last_mut() {
+            for (page_no, page) in released.saved {
+                below.saved.entry(page_no).or_insert(page);
+            }
+        }
+    }
+
+    /// Undoes the changes made since the newest savepoint and removes it
+    pub fn rollback_savepoint(&mut self){
+        let savepoint = match self.savepoints.pop() {
+            Some(savepoint) => savepoint,
+            None => return,
+        };
+
+        for (page_no, page) in savepoint.saved {
+            match page {
+                Some(page) => { self.cache.insert(page_no, page); },
+                None => { self.cache.remove(&page_no); },
+            }
+        }
+        self.page_count = savepoint.page_count;
+        self.freelist = savepoint.freelist;
+    }
+
+    fn header(&self) -> Vec<u8>{
+        let mut header = vec![0u8; PAGE_SIZE];
+        header[0..16].copy_from_slice(MAGIC);
+        write_u32(&mut header, 16, PAGE_SIZE as u32);
+        write_u32(&mut header, 20, self.page_count);
+        write_u32(&mut header, 24, self.freelist);
+        header
+    }
+
+    fn evict(&mut self){
+        while self.cache.len() >= CACHE_PAGES {
+            let oldest = self.cache.iter()
+                .filter(|(_, page)| !page.dirty)
+                .min_by_key(|(_, page)| page.used)
+                .map(|(page_no, _)| *page_no);
+
+            match oldest {
+                Some(page_no) => { self.cache.remove(&page_no); },
+                None => break,
+            }
+        }
+    }
+
+    fn read_raw(&mut self, page_no: PageNo) -> Result<Vec<u8>, Error>{
+        match &mut self.backend {
+            Backend::File{ file, .. } => {
+                let mut data = vec![0u8; PAGE_SIZE];
+                file.seek(SeekFrom::Start(page_no as u64 * PAGE_SIZE as u64))?;
+                file.read_exact(&mut data)?;
+                Ok(data)
+            },
+            Backend::Memory(pages) => Ok(pages.get(page_no as usize)
+                .cloned()
+                .unwrap_or_else(|| vec![0u8; PAGE_SIZE])),
+        }
+    }
+
+    fn write_raw(&mut self, page_no: PageNo, data: &[u8]) -> Result<(), Error>{
+        match &mut self.backend {
+            Backend::File{ file, .. } => {
+                file.seek(SeekFrom::Start(page_no as u64 * PAGE_SIZE as u64))?;
+                file.write_all(data)?;
+            },
+            Backend::Memory(pages) => {
+                let idx = page_no as usize;
+                if pages.len() <= idx {
+                    pages.resize(idx + 1, vec![0u8; PAGE_SIZE]);
+                }
+                pages[idx] = data.to_vec();
+            },
+        }
+        Ok(())
+    }
+}
+
+pub fn read_u16(page: &[u8], offset: usize) -> u16{
+    u16::from_be_bytes([page[offset], page[offset + 1]])
+}
+
+pub fn write_u16(page: &mut [u8], offset: usize, val: u16){
+    page[offset..offset + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+pub fn read_u32(page: &[u8], offset: usize) -> u32{
+    u32::from_be_bytes([page[offset], page[offset + 1], page[offset + 2], page[offset + 3]])
+}
+
+pub fn write_u32(page: &mut [u8], offset: usize, val: u32){
+    page[offset..offset + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf{
+        let path = std::env::temp_dir().join(format!("rustqlite-pager-{}-{}.rdb", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(journal::path_for(&path));
+        path
+    }
+
+    fn page(fill: u8) -> Vec<u8>{
+        vec![fill; PAGE_SIZE]
+    }
+
+    /// The clean page that was used the longest time ago
+    fn oldest_clean(pager: &Pager) -> Option<PageNo>{
+        pager.cache.iter()
+            .filter(|(_, page)| !page.dirty)
+            .min_by_key(|(_, page)| page.used)
+            .map(|(page_no, _)| *page_no)
+    }
+
+    #[test]
+    fn dirty_pages_stay_cached_until_commit() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let count = CACHE_PAGES as u32 + 100;
+
+        for n in 0..count {
+            let page_no = pager.allocate()?;
+            pager.write(page_no, page(n as u8))?;
+        }
+        assert_eq!(pager.cache.len(), count as usize);
+
+        pager.commit()?;
+        assert!(pager.cache.len() < CACHE_PAGES);
+
+        // evicted pages are read back from where the commit put them
+        for page_no in 1..=count {
+            assert_eq!(pager.read(page_no)?, page((page_no - 1) as u8));
+        }
+        assert!(pager.cache.len() <= CACHE_PAGES);
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_clean_page() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let count = CACHE_PAGES as u32 + 10;
+        for _ in 0..count {
+            pager.allocate()?;
+        }
+        pager.commit()?;
+
+        // a dirty page is kept however long ago it was used
+        pager.write(1, page(9))?;
+        for page_no in 2..=count {
+            pager.read(page_no)?;
+        }
+
+        let oldest = oldest_clean(&pager).expect("clean pages are cached");
+        let uncached = (2..=count).find(|page_no| !pager.cache.contains_key(page_no))
+            .expect("some pages were evicted");
+
+        pager.read(uncached)?;
+        assert!(!pager.cache.contains_key(&oldest));
+        assert!(pager.cache.contains_key(&uncached));
+        assert_eq!(pager.read(1)?, page(9));
+        assert!(pager.cache[&1].dirty);
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_throws_away_uncommitted_pages() -> Result<(), Error>{
+        let mut pager = Pager::memory();
+        let page_no = pager.allocate()?;
+        pager.write(page_no, page(1))?;
+        pager.commit()?;
+
+        pager.write(page_no, page(2))?;
+        let added = pager.allocate()?;
+        pager.rollback();
+
+        assert!(!pager.is_dirty());
+        assert_eq!(pager.read(page_no)?, page(1));
+        assert!(pager.read(added).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pages_and_freelist_survive_reopening() -> Result<(), Error>{
+        let path = temp_path("reopen");
+        {
+            let mut pager = Pager::open(&path)?;
+            assert!(pager.is_new());
+            for n in 1..=3 {
+                let page_no = pager.allocate()?;
+                pager.write(page_no, page(n))?;
+            }
+            pager.free(2)?;
+            pager.commit()?;
+        }
+
+        let mut pager = Pager::open(&path)?;
+        assert!(!pager.is_new());
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.read(1)?, page(1));
+        assert_eq!(pager.read(3)?, page(3));
+        // the freed page is handed out again rather than a new one
+        assert_eq!(pager.allocate()?, 2);
+        assert_eq!(pager.page_count(), 4);
+
+        drop(pager);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
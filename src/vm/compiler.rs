@@ -0,0 +1,422 @@
+use crate::interpreter::stmt::Expr;
+use crate::interpreter::token::{Literal, TokenType};
+use crate::vm::plan::Plan;
+use crate::vm::program::{Address, Op, Program, Register, Slot};
+use crate::vm::table::Table;
+
+/// Builds a program op by op, handing out registers and slots as they are
+/// needed. Jumps to ops that are not there yet are emitted with a target
+/// of 0 and pointed at the right address once it is known
+struct Builder {
+    program: Program,
+    /// Jumps that leave the program, pointed at its Halt when it is finished
+    exits: Vec<Address>,
+}
+
+impl Builder {
+    fn new() -> Self{
+        Builder {
+            program: Program::default(),
+            exits: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> Address{
+        self.program.ops.push(op);
+        self.program.ops.len() - 1
+    }
+
+    /// Address of the next op to be emitted
+    fn here(&self) -> Address{
+        self.program.ops.len()
+    }
+
+    fn patch(&mut self, jump: Address, target: Address){
+        if let Some(to) = self.program.ops[jump].target_mut() {
+            *to = target;
+        }
+    }
+
+    /// Points a jump at the op emitted next
+    fn patch_here(&mut self, jump: Address){
+        self.patch(jump, self.here());
+    }
+
+    /// `count` registers in a row, returns the first one
+    fn registers(&mut self, count: usize) -> Register{
+        let start = self.program.registers;
+        self.program.registers += count;
+        start
+    }
+
+    fn register(&mut self) -> Register{
+        self.registers(1)
+    }
+
+    fn slot(&mut self) -> Slot{
+        self.program.slots += 1;
+        self.program.slots - 1
+    }
+
+    fn finish(mut self) -> Program{
+        let halt = self.emit(Op::Halt);
+        for jump in std::mem::take(&mut self.exits) {
+            self.patch(jump, halt);
+        }
+        self.program
+    }
+
+    /// Emits a loop over the rows an access plan reads. `body` is emitted
+    /// once, and runs with the cursor on each row that passes the filter
+    fn each_row(&mut self, table: &Table, cursor: Slot, plan: &Plan, body: impl FnOnce(&mut Builder)){
+        let (predicate, source) = match plan {
+            Plan::Filter{input, predicate} => (Some(predicate), input.as_ref()),
+            _ => (None, plan),
+        };
+
+        self.emit(Op::OpenTable{ cursor, table: table.clone() });
+        let seek = match source {
+            Plan::IdLookup{id, ..} => {
+                let src = self.register();
+                self.emit(Op::Value{ value: Literal::Number(*id), dest: src });
+                self.emit(Op::SeekRowid{ cursor, id: src, target: 0 })
+            },
+            Plan::IndexRangeScan{index, equal, lower, upper, descending, ..} => self.emit(Op::SeekIndex{
+                cursor,
+                index: index.clone(),
+                equal: equal.clone(),
+                lower: lower.clone(),
+                upper: upper.clone(),
+                descending: *descending,
+                target: 0,
+            }),
+            _ => self.emit(Op::Rewind{ cursor, target: 0 }),
+        };
+
+        let top = self.here();
+        let skip = predicate.map(|predicate| {
+            let src = self.expr(cursor, predicate);
+            self.emit(Op::IfNot{ src, target: 0 })
+        });
+
+        body(self);
+
+        if let Some(skip) = skip {
+            self.patch_here(skip);
+        }
+        self.emit(Op::Next{ cursor, target: top });
+        self.patch_here(seek);
+    }
+
+    /// Emits the ops computing an expression for the row a cursor points
+    /// at, returns the register that holds its value
+    fn expr(&mut self, cursor: Slot, expr: &Expr) -> Register{
+        match expr {
+            Expr::Literal(value) => {
+                let dest = self.register();
+                self.emit(Op::Value{ value: value.clone(), dest });
+                dest
+            },
+            Expr::Column(column) => {
+                let dest = self.register();
+                self.emit(Op::Column{ cursor, column: column.clone(), dest });
+                dest
+            },
+            Expr::Grouping(inner) => self.expr(cursor, inner),
+            Expr::IsNull{expr, negated} => {
+                let src = self.expr(cursor, expr);
+                let dest = self.register();
+                self.emit(Op::IsNull{ src, negated: *negated, dest });
+                dest
+            },
+            Expr::Unary{right, ..} => {
+                let src = self.expr(cursor, right);
+                let dest = self.register();
+                self.emit(Op::Not{ src, dest });
+                dest
+            },
+            // the right hand side is skipped once the left decides the result
+            Expr::Binary{left, operator: TokenType::And, right} => {
+                let dest = self.expr(cursor, left);
+                let skip = self.emit(Op::IfFalse{ src: dest, target: 0 });
+                let rhs = self.expr(cursor, right);
+                self.emit(Op::And{ lhs: dest, rhs, dest });
+                self.patch_here(skip);
+                dest
+            },
+            Expr::Binary{left, operator: TokenType::Or, right} => {
+                let dest = self.expr(cursor, left);
+                let skip = self.emit(Op::IfTrue{ src: dest, target: 0 });
+                let rhs = self.expr(cursor, right);
+                self.emit(Op::Or{ lhs: dest, rhs, dest });
+                self.patch_here(skip);
+                dest
+            },
+            Expr::Binary{left, operator, right} => {
+                let lhs = self.expr(cursor, left);
+                let rhs = self.expr(cursor, right);
+                let dest = self.register();
+                self.emit(Op::Compare{ operator: operator.clone(), lhs, rhs, dest });
+                dest
+            },
+        }
+    }
+
+    /// Reads the columns of the row a cursor points at into the result,
+    /// counting down the limit when there is one
+    fn result_row(&mut self, cursor: Slot, columns: &[String], limit: Option<Register>){
+        let start = self.registers(columns.len());
+        for (idx, column) in columns.iter().enumerate() {
+            self.emit(Op::Column{ cursor, column: column.clone(), dest: start + idx });
+        }
+        self.emit(Op::ResultRow{ start, count: columns.len() });
+
+        if let Some(src) = limit {
+            let exit = self.emit(Op::DecrJumpZero{ src, target: 0 });
+            self.exits.push(exit);
+        }
+    }
+
+    /// Loads values into registers in a row, returns the first one
+    fn values(&mut self, values: &[Literal]) -> Register{
+        let start = self.registers(values.len());
+        for (idx, value) in values.iter().enumerate() {
+            self.emit(Op::Value{ value: value.clone(), dest: start + idx });
+        }
+        start
+    }
+
+    /// Collects the ids of the rows a plan reads into a row set first, so
+    /// that changing the rows cannot change which rows are visited. Then
+    /// emits `change` once, to run with the id of each row in a register
+    fn each_id(&mut self, table: &Table, plan: &Plan, change: impl FnOnce(&mut Builder, Slot, Register)){
+        let cursor = self.slot();
+        let rowset = self.slot();
+
+        self.each_row(table, cursor, plan, |builder| {
+            let src = builder.register();
+            builder.emit(Op::Rowid{ cursor, dest: src });
+            builder.emit(Op::RowSetAdd{ rowset, src });
+        });
+
+        let id = self.register();
+        let top = self.emit(Op::RowSetRead{ rowset, dest: id, target: 0 });
+        change(self, cursor, id);
+        self.emit(Op::Goto{ target: top });
+        self.patch_here(top);
+    }
+}
+
+/// Compiles a SELECT from its plan. Rows are handed back as they are read,
+/// or collected and handed back in order when the plan sorts them
+pub fn select(table: &Table, plan: &Plan) -> Program{
+    let mut builder = Builder::new();
+
+    let (limit, plan) = match plan {
+        Plan::Limit{input, count} => (Some(*count), input.as_ref()),
+        _ => (None, plan),
+    };
+    let (columns, plan) = match plan {
+        Plan::Project{input, columns} => (columns.clone(), input.as_ref()),
+        _ => (table.columns.iter().map(|col| col.name.clone()).collect(), plan),
+    };
+    let (keys, plan) = match plan {
+        Plan::Sort{input, keys} => (Some(keys), input.as_ref()),
+        _ => (None, plan),
+    };
+
+    builder.program.types = columns.iter()
+        .map(|col| table.schema.get(col).cloned().unwrap_or_default())
+        .collect();
+    builder.program.columns = columns.clone();
+
+    let limit = limit.map(|count| {
+        let src = builder.register();
+        builder.emit(Op::Value{ value: Literal::Number(count as i64), dest: src });
+        if count == 0 {
+            let exit = builder.emit(Op::Goto{ target: 0 });
+            builder.exits.push(exit);
+        }
+        src
+    });
+
+    let cursor = builder.slot();
+    match keys {
+        Some(keys) => {
+            let sorter = builder.slot();
+            builder.emit(Op::SorterOpen{ cursor: sorter, keys: keys.clone() });
+            builder.each_row(table, cursor, plan, |builder| {
+                builder.emit(Op::SorterInsert{ cursor: sorter, from: cursor });
+            });
+
+            let sort = builder.emit(Op::SorterSort{ cursor: sorter, target: 0 });
+            let top = builder.here();
+            builder.result_row(sorter, &columns, limit);
+            builder.emit(Op::Next{ cursor: sorter, target: top });
+            builder.patch_here(sort);
+        },
+        None => builder.each_row(table, cursor, plan, |builder| builder.result_row(cursor, &columns, limit)),
+    }
+
+    builder.finish()
+}
+
+/// Compiles an INSERT of values into the named columns of a table
+pub fn insert(table: &Table, columns: &[String], values: &[Literal]) -> Program{
+    let mut builder = Builder::new();
+    let cursor = builder.slot();
+
+    builder.emit(Op::OpenTable{ cursor, table: table.clone() });
+    let start = builder.values(values);
+    builder.emit(Op::Insert{ cursor, columns: columns.to_vec(), start });
+    builder.finish()
+}
+
+/// Compiles an UPDATE that sets the named columns of the rows a plan reads
+pub fn update(table: &Table, plan: &Plan, columns: &[String], values: &[Literal]) -> Program{
+    let mut builder = Builder::new();
+    builder.each_id(table, plan, |builder, cursor, id| {
+        let start = builder.values(values);
+        builder.emit(Op::Update{ cursor, id, columns: columns.to_vec(), start });
+    });
+    builder.finish()
+}
+
+/// Compiles a DELETE of the rows a plan reads
+pub fn delete(table: &Table, plan: &Plan) -> Program{
+    let mut builder = Builder::new();
+    builder.each_id(table, plan, |builder, cursor, id| {
+        builder.emit(Op::Delete{ cursor, id });
+    });
+    builder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::plan;
+    use crate::vm::test_util::{names, people, where_clause};
+    use crate::{Connection, Error};
+
+    fn compile_select(condition: Option<&str>, limit: Option<usize>) -> Program{
+        let table = people();
+        let predicate = condition.map(where_clause);
+        select(&table, &plan::select(&table, &[String::from("name")], predicate.as_ref(), &[], limit))
+    }
+
+    fn position(program: &Program, name: &str) -> Address{
+        program.ops.iter().position(|op| op.name() == name)
+            .unwrap_or_else(|| panic!("no {} in {:?}", name, program.ops))
+    }
+
+    fn target(op: &Op) -> Address{
+        op.clone().target_mut().map(|target| *target).expect("op does not jump")
+    }
+
+    /// ada 30, bob NULL, cid 20, dan 40
+    fn database() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE people (name varchar, age int);
+                      CREATE INDEX byage ON people (age);
+                      INSERT INTO people (name, age) VALUES ('ada', 30);
+                      INSERT INTO people (name) VALUES ('bob');
+                      INSERT INTO people (name, age) VALUES ('cid', 20);
+                      INSERT INTO people (name, age) VALUES ('dan', 40);")?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn and_and_or_skip_their_right_side(){
+        for (condition, jump, join) in [("name = 'a' AND name = 'b'", "IfFalse", "And"),
+                                        ("name = 'a' OR name = 'b'", "IfTrue", "Or")] {
+            let program = compile_select(Some(condition), None);
+            let jump = position(&program, jump);
+            let join = position(&program, join);
+
+            // the jump goes past the right side and the op joining the two
+            assert!(jump < join);
+            assert_eq!(target(&program.ops[jump]), join + 1, "{}", condition);
+            assert!(program.ops[jump + 1..join].iter().any(|op| op.name() == "Compare"));
+            assert!(matches!(program.ops[join + 1], Op::IfNot{..}));
+        }
+    }
+
+    #[test]
+    fn limit_jumps_to_halt(){
+        let program = compile_select(None, Some(2));
+        let halt = program.ops.len() - 1;
+        assert!(matches!(program.ops[halt], Op::Halt));
+        assert_eq!(target(&program.ops[position(&program, "DecrJumpZero")]), halt);
+
+        // with nothing to hand back the program halts before opening the table
+        let program = compile_select(None, Some(0));
+        let goto = position(&program, "Goto");
+        assert_eq!(target(&program.ops[goto]), program.ops.len() - 1);
+        assert!(goto < position(&program, "OpenTable"));
+    }
+
+    #[test]
+    fn limit_stops_after_that_many_rows() -> Result<(), Error>{
+        let mut conn = database()?;
+        assert_eq!(names(&mut conn, "SELECT name FROM people LIMIT 0;")?, Vec::<String>::new());
+        assert_eq!(names(&mut conn, "SELECT name FROM people LIMIT 1;")?, ["ada"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people LIMIT 3;")?, ["ada", "bob", "cid"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people LIMIT 10;")?.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn changes_wait_until_every_row_is_found(){
+        let table = people();
+        let plan = plan::access(&table, Some(&where_clause("age > 20")));
+
+        for program in [update(&table, &plan, &[String::from("age")], &[Literal::Number(50)]), delete(&table, &plan)] {
+            let read = position(&program, "RowSetRead");
+            let change = program.ops.iter().position(|op| matches!(op, Op::Update{..} | Op::Delete{..})).unwrap();
+
+            // ids are collected by the scan, and only changed once it is done
+            assert!(position(&program, "RowSetAdd") < position(&program, "Next"));
+            assert!(position(&program, "Next") < read);
+            assert!(read < change);
+            assert!(matches!(program.ops[change + 1], Op::Goto{target} if target == read));
+            assert_eq!(target(&program.ops[read]), program.ops.len() - 1);
+        }
+    }
+
+    #[test]
+    fn update_of_an_indexed_column_over_its_index() -> Result<(), Error>{
+        let mut conn = database()?;
+
+        // the rows moved past the end of the range are not visited again
+        assert_eq!(conn.execute("UPDATE people SET age = 50 WHERE age >= 30;")?, 2);
+        assert_eq!(names(&mut conn, "SELECT name FROM people WHERE age = 50;")?, ["ada", "dan"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people WHERE age >= 30;")?, ["ada", "dan"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people WHERE age = 30;")?, Vec::<String>::new());
+
+        // nor are rows moved to ids the scan has yet to reach
+        assert_eq!(conn.execute("UPDATE people SET id = 10 WHERE name = 'ada';")?, 1);
+        assert_eq!(names(&mut conn, "SELECT name FROM people WHERE id = 10;")?, ["ada"]);
+
+        assert_eq!(conn.execute("DELETE FROM people WHERE age > 0;")?, 3);
+        assert_eq!(names(&mut conn, "SELECT name FROM people;")?, ["bob"]);
+        Ok(())
+    }
+
+    #[test]
+    fn where_treats_null_as_unknown() -> Result<(), Error>{
+        let mut conn = database()?;
+        let query = |conn: &mut Connection, condition: &str| names(conn, &format!("SELECT name FROM people WHERE {};", condition));
+
+        // a comparison with NULL is neither true nor false
+        assert_eq!(query(&mut conn, "age > 25")?, ["ada", "dan"]);
+        assert_eq!(query(&mut conn, "NOT age > 25")?, ["cid"]);
+        assert_eq!(query(&mut conn, "age = NULL")?, Vec::<String>::new());
+        assert_eq!(query(&mut conn, "age IS NULL")?, ["bob"]);
+
+        // unknown OR true is true, unknown AND false is false
+        assert_eq!(query(&mut conn, "age > 25 OR name = 'bob'")?, ["ada", "bob", "dan"]);
+        assert_eq!(query(&mut conn, "NOT (age > 25 AND name = 'zed')")?, ["ada", "bob", "cid", "dan"]);
+        assert_eq!(query(&mut conn, "NOT (age > 25 OR name = 'zed')")?, ["cid"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use crate::error::Error;
+use crate::interpreter::token::Literal;
+use crate::vm::evaluator;
+use crate::vm::program::{Op, Program};
+use crate::vm::rows::Rows;
+use crate::vm::storage::{RowCursor, Storage};
+use crate::vm::table::{Column, Row, Table};
+use crate::vm::virtualmachine::VirtualMachine;
+
+/// Where a cursor gets its rows from
+enum Source {
+    /// Nothing yet, the cursor has been opened but not moved
+    Closed,
+    /// Every row of the table in id order, read as the cursor moves
+    Rows(RowCursor),
+    /// The rows with these ids, looked up as the cursor moves
+    Ids(std::vec::IntoIter<i64>),
+    /// A sorter still collecting rows
+    Sorter{
+        keys: Vec<(String, bool)>,
+        rows: Vec<(i64, Row)>,
+    },
+    /// A sorter handing back the rows it collected, in order
+    Sorted(std::vec::IntoIter<(i64, Row)>),
+}
+
+struct Cursor {
+    table: Option<Table>,
+    source: Source,
+    current: Option<(i64, Row)>,
+    /// The columns of other tables referring to this table, looked
+    /// up the first time a row of it is updated or deleted
+    children: Option<Vec<(Table, Column)>>,
+}
+
+impl Cursor {
+    fn new(table: Option<Table>, source: Source) -> Self{
+        Cursor {
+            table,
+            source,
+            current: None,
+            children: None,
+        }
+    }
+
+    fn table(&self) -> Result<&Table, Error>{
+        self.table.as_ref().ok_or_else(|| Error::Execution(String::from("cursor has no table")))
+    }
+
+    /// Moves to the next row, returns false once there are none left
+    fn advance(&mut self, storage: &mut Storage) -> Result<bool, Error>{
+        self.current = match &mut self.source {
+            Source::Rows(rows) => storage.next_row(rows)?,
+            Source::Ids(ids) => {
+                let table = self.table.as_ref()
+                    .ok_or_else(|| Error::Execution(String::from("cursor has no table")))?;
+                let mut found = None;
+                for id in ids.by_ref() {
+                    if let Some(row) = storage.row(table, id)? {
+                        found = Some((id, row));
+                        break;
+                    }
+                }
+                found
+            },
+            Source::Sorted(rows) => rows.next(),
+            Source::Closed | Source::Sorter{..} => None,
+        };
+        Ok(self.current.is_some())
+    }
+
+    fn current(&self) -> Result<&(i64, Row), Error>{
+        self.current.as_ref().ok_or_else(|| Error::Execution(String::from("cursor is not on a row")))
+    }
+
+    fn children(&mut self, storage: &mut Storage) -> Result<Vec<(Table, Column)>, Error>{
+        if self.children.is_none() {
+            self.children = Some(VirtualMachine::referencing(storage, &self.table()?.name)?);
+        }
+        Ok(self.children.clone().unwrap_or_default())
+    }
+}
+
+/// Runs a program against a database until it halts. Returns the rows
+/// the program handed back, and how many rows it inserted, updated or
+/// deleted
+pub fn run(storage: &mut Storage, program: &Program) -> Result<(Rows, usize), Error>{
+    let mut registers: Vec<Literal> = vec![Literal::Null; program.registers];
+    let mut cursors: Vec<Cursor> = (0..program.slots).map(|_| Cursor::new(None, Source::Closed)).collect();
+    let mut rowsets: Vec<VecDeque<i64>> = vec![VecDeque::new(); program.slots];
+    let mut result = Rows::new(program.columns.clone(), program.types.clone());
+    let mut changes: usize = 0;
+    let mut pc = 0;
+
+    loop {
+        let op = program.ops.get(pc)
+            .ok_or_else(|| Error::Execution(format!("program jumped to {}, past its end", pc)))?;
+        pc += 1;
+
+        match op {
+            Op::OpenTable{cursor, table} => cursors[*cursor] = Cursor::new(Some(table.clone()), Source::Closed),
+            Op::Rewind{cursor, target} => {
+                let cursor = &mut cursors[*cursor];
+                cursor.source = Source::Rows(storage.rows(cursor.table()?)?);
+                if !cursor.advance(storage)? {
+                    pc = *target;
+                }
+            },
+            Op::SeekRowid{cursor, id, target} => {
+                let ids = match &registers[*id] {
+                    Literal::Number(id) => vec![*id],
+                    _ => Vec::new(),
+                };
+                let cursor = &mut cursors[*cursor];
+                cursor.source = Source::Ids(ids.into_iter());
+                if !cursor.advance(storage)? {
+                    pc = *target;
+                }
+            },
+            Op::SeekIndex{cursor, index, equal, lower, upper, descending, target} => {
+                let mut ids = storage.index_range(index, equal, lower.as_ref(), upper.as_ref())?;
+                if *descending {
+                    ids.reverse();
+                }
+                let cursor = &mut cursors[*cursor];
+                cursor.source = Source::Ids(ids.into_iter());
+                if !cursor.advance(storage)? {
+                    pc = *target;
+                }
+            },
+            Op::Next{cursor, target} => {
+                if cursors[*cursor].advance(storage)? {
+                    pc = *target;
+                }
+            },
+            Op::Column{cursor, column, dest} => {
+                let (_, row) = cursors[*cursor].current()?;
+                registers[*dest] = row.values.get(column).cloned().unwrap_or(Literal::Null);
+            },
+            Op::Rowid{cursor, dest} => {
+                let (id, _) = cursors[*cursor].current()?;
+                registers[*dest] = Literal::Number(*id);
+            },
+            Op::Value{value, dest} => registers[*dest] = value.clone(),
+            Op::Compare{operator, lhs, rhs, dest} =>
+                registers[*dest] = evaluator::compare(operator, &registers[*lhs], &registers[*rhs])?,
+            Op::And{lhs, rhs, dest} => registers[*dest] = evaluator::and(&registers[*lhs], &registers[*rhs])?,
+            Op::Or{lhs, rhs, dest} => registers[*dest] = evaluator::or(&registers[*lhs], &registers[*rhs])?,
+            Op::Not{src, dest} => registers[*dest] = evaluator::not(&registers[*src])?,
+            Op::IsNull{src, negated, dest} =>
+                registers[*dest] = Literal::Boolean((registers[*src] == Literal::Null) != *negated),
+            Op::IfNot{src, target} => {
+                if evaluator::truth_of(&registers[*src])? != Some(true) {
+                    pc = *target;
+                }
+            },
+            Op::IfFalse{src, target} => {
+                if evaluator::truth_of(&registers[*src])? == Some(false) {
+                    pc = *target;
+                }
+            },
+            Op::IfTrue{src, target} => {
+                if evaluator::truth_of(&registers[*src])? == Some(true) {
+                    pc = *target;
+                }
+            },
+            Op::Goto{target} => pc = *target,
+            Op::ResultRow{start, count} => result.rows.push(registers[*start..*start + *count].to_vec()),
+            Op::DecrJumpZero{src, target} => {
+                if let Literal::Number(count) = &mut registers[*src] {
+                    *count -= 1;
+                    if *count <= 0 {
+                        pc = *target;
+                    }
+                }
+            },
+            Op::SorterOpen{cursor, keys} =>
+                cursors[*cursor] = Cursor::new(None, Source::Sorter{ keys: keys.clone(), rows: Vec::new() }),
+            Op::SorterInsert{cursor, from} => {
+                let row = cursors[*from].current()?.clone();
+                if let Source::Sorter{rows, ..} = &mut cursors[*cursor].source {
+                    rows.push(row);
+                }
+            },
+            Op::SorterSort{cursor, target} => {
+                let cursor = &mut cursors[*cursor];
+                if let Source::Sorter{keys, rows} = &mut cursor.source {
+                    let mut rows = std::mem::take(rows);
+                    rows.sort_by(|(_, lhs), (_, rhs)| order(keys, lhs, rhs));
+                    cursor.source = Source::Sorted(rows.into_iter());
+                }
+                if !cursor.advance(storage)? {
+                    pc = *target;
+                }
+            },
+            Op::RowSetAdd{rowset, src} => {
+                if let Literal::Number(id) = registers[*src] {
+                    rowsets[*rowset].push_back(id);
+                }
+            },
+            Op::RowSetRead{rowset, dest, target} => match rowsets[*rowset].pop_front() {
+                Some(id) => registers[*dest] = Literal::Number(id),
+                None => pc = *target,
+            },
+            Op::Insert{cursor, columns, start} => {
+                let values = &registers[*start..*start + columns.len()];
+                let table = cursors[*cursor].table.as_mut()
+                    .ok_or_else(|| Error::Execution(String::from("cursor has no table")))?;
+                VirtualMachine::insert_row(storage, table, columns, values)?;
+                changes += 1;
+            },
+            Op::Update{cursor, id, columns, start} => {
+                let Literal::Number(id) = registers[*id] else { continue };
+                let values = &registers[*start..*start + columns.len()];
+                let cursor = &mut cursors[*cursor];
+                let children = cursor.children(storage)?;
+                let table = cursor.table.as_mut()
+                    .ok_or_else(|| Error::Execution(String::from("cursor has no table")))?;
+                VirtualMachine::update_row(storage, table, &children, id, columns, values)?;
+                changes += 1;
+            },
+            Op::Delete{cursor, id} => {
+                let Literal::Number(id) = registers[*id] else { continue };
+                let cursor = &mut cursors[*cursor];
+                let children = cursor.children(storage)?;
+                if VirtualMachine::delete_row(storage, cursor.table()?, id, &children)? {
+                    changes += 1;
+                }
+            },
+            Op::Halt => return Ok((result, changes)),
+        }
+    }
+}
+
+/// Orders two rows by each key in turn
+fn order(keys: &[(String, bool)], lhs: &Row, rhs: &Row) -> Ordering{
+    keys.iter()
+        .map(|(column, descending)| {
+            let ordering = compare(lhs.values.get(column), rhs.values.get(column));
+            if *descending { ordering.reverse() } else { ordering }
+        })
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Orders two values of a column, NULL before every other value
+fn compare(lhs: Option<&Literal>, rhs: Option<&Literal>) -> Ordering{
+    let lhs = lhs.unwrap_or(&Literal::Null);
+    let rhs = rhs.unwrap_or(&Literal::Null);
+
+    match (lhs, rhs) {
+        (Literal::Null, Literal::Null) => Ordering::Equal,
+        (Literal::Null, _) => Ordering::Less,
+        (_, Literal::Null) => Ordering::Greater,
+        _ => lhs.partial_cmp(rhs).unwrap_or(Ordering::Equal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::test_util::{names, query_plan};
+    use crate::{Connection, Error};
+
+    /// Ages are all different so every ORDER BY age has one right answer
+    fn database() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE people (name varchar, age int, city varchar);
+                      INSERT INTO people (name, age, city) VALUES ('ada', 30, 'oslo');
+                      INSERT INTO people (name, city) VALUES ('bob', 'oslo');
+                      INSERT INTO people (name, age, city) VALUES ('cid', 20, 'rome');
+                      INSERT INTO people (name, age) VALUES ('dan', 40);
+                      INSERT INTO people (name, age, city) VALUES ('eve', 25, 'rome');")?;
+        Ok(conn)
+    }
+
+    /// The detail of each node of the plan, from the outermost in
+    fn details(conn: &mut Connection, sql: &str) -> Result<Vec<String>, Error>{
+        Ok(query_plan(conn, sql)?.into_iter().map(|(_, _, detail)| detail).collect())
+    }
+
+    fn sorts(conn: &mut Connection, sql: &str) -> Result<bool, Error>{
+        Ok(details(conn, sql)?.iter().any(|detail| detail.starts_with("SORT")))
+    }
+
+    #[test]
+    fn order_by_reads_an_index_in_either_direction() -> Result<(), Error>{
+        let mut conn = database()?;
+        conn.execute("CREATE INDEX byage ON people (age);")?;
+
+        let sql = "SELECT name FROM people ORDER BY age;";
+        assert_eq!(details(&mut conn, sql)?, ["PROJECT name", "SCAN people USING INDEX byage"]);
+        assert_eq!(names(&mut conn, sql)?, ["bob", "cid", "eve", "ada", "dan"]);
+
+        // NULL sorts first, so reading the index backwards puts it last
+        let sql = "SELECT name FROM people ORDER BY age DESC;";
+        assert_eq!(details(&mut conn, sql)?, ["PROJECT name", "SCAN people USING INDEX byage DESC"]);
+        assert_eq!(names(&mut conn, sql)?, ["dan", "ada", "eve", "cid", "bob"]);
+
+        let sql = "SELECT name FROM people WHERE age > 20 ORDER BY age DESC;";
+        assert!(details(&mut conn, sql)?.contains(&String::from("SEARCH people USING INDEX byage (age>20) DESC")));
+        assert_eq!(names(&mut conn, sql)?, ["dan", "ada", "eve"]);
+        Ok(())
+    }
+
+    #[test]
+    fn order_by_columns_held_equal_reads_the_rest_of_the_index() -> Result<(), Error>{
+        let mut conn = database()?;
+        conn.execute("CREATE INDEX bycity ON people (city, age);")?;
+
+        let sql = "SELECT name FROM people WHERE city = 'rome' ORDER BY age DESC;";
+        assert!(!sorts(&mut conn, sql)?);
+        assert_eq!(names(&mut conn, sql)?, ["eve", "cid"]);
+
+        let sql = "SELECT name FROM people ORDER BY city DESC, age DESC;";
+        assert!(!sorts(&mut conn, sql)?);
+        assert_eq!(names(&mut conn, sql)?, ["eve", "cid", "ada", "bob", "dan"]);
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_directions_are_sorted() -> Result<(), Error>{
+        let mut conn = database()?;
+        conn.execute("CREATE INDEX bycity ON people (city, age);")?;
+
+        // an index only runs one way, so keys going both ways need a sort
+        let sql = "SELECT name FROM people ORDER BY city, age DESC;";
+        assert_eq!(details(&mut conn, sql)?, ["PROJECT name", "SORT BY city, age DESC", "SCAN people"]);
+        assert_eq!(names(&mut conn, sql)?, ["dan", "ada", "bob", "eve", "cid"]);
+
+        let sql = "SELECT name FROM people ORDER BY city DESC, age;";
+        assert_eq!(names(&mut conn, sql)?, ["cid", "eve", "bob", "ada", "dan"]);
+        Ok(())
+    }
+
+    #[test]
+    fn sorter_puts_nulls_first_ascending_and_last_descending() -> Result<(), Error>{
+        let mut conn = database()?;
+
+        assert!(sorts(&mut conn, "SELECT name FROM people ORDER BY age;")?);
+        assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY age;")?, ["bob", "cid", "eve", "ada", "dan"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY age DESC;")?, ["dan", "ada", "eve", "cid", "bob"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY city;")?[0], "dan");
+        assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY city DESC;")?[4], "dan");
+        Ok(())
+    }
+
+    #[test]
+    fn limit_applies_after_ordering() -> Result<(), Error>{
+        let mut conn = database()?;
+
+        // sorted first, then read through an index
+        for setup in ["", "CREATE INDEX byage ON people (age);"] {
+            if !setup.is_empty() {
+                conn.execute(setup)?;
+            }
+            assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY age DESC LIMIT 2;")?, ["dan", "ada"]);
+            assert_eq!(names(&mut conn, "SELECT name FROM people ORDER BY age LIMIT 1;")?, ["bob"]);
+            assert_eq!(names(&mut conn, "SELECT name FROM people WHERE age < 40 ORDER BY age DESC LIMIT 2;")?, ["ada", "eve"]);
+            assert!(names(&mut conn, "SELECT name FROM people ORDER BY age LIMIT 0;")?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn index_and_sorter_agree() -> Result<(), Error>{
+        let queries = [
+            "SELECT name, age FROM people ORDER BY age;",
+            "SELECT name, age FROM people ORDER BY age DESC;",
+            "SELECT name, age FROM people WHERE age >= 25 ORDER BY age DESC;",
+            "SELECT name, age FROM people WHERE city = 'oslo' ORDER BY age;",
+            "SELECT * FROM people ORDER BY age DESC LIMIT 3;",
+        ];
+
+        let mut conn = database()?;
+        let mut sorted = Vec::new();
+        for sql in queries {
+            assert!(sorts(&mut conn, sql)?, "{}", sql);
+            sorted.push(conn.query(sql)?);
+        }
+
+        conn.execute("CREATE INDEX byage ON people (age);")?;
+        for (sql, sorted) in queries.iter().zip(sorted) {
+            assert!(!sorts(&mut conn, sql)?, "{}", sql);
+            assert_eq!(conn.query(sql)?, sorted, "{}", sql);
+        }
+        Ok(())
+    }
+}
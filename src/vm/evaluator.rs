@@ -0,0 +1,234 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::interpreter::stmt::Expr;
+use crate::interpreter::token::{Literal, TokenType};
+use crate::vm::table::Row;
+
+/// Walks a where clause before any rows are touched, so that unknown
+/// columns and column-type mismatches are reported even on empty tables
+pub fn validate_expr(expr: &Expr, schema: &HashMap<String, String>) -> Result<(), Error>{
+    match expr {
+        Expr::Literal(_) => Ok(()),
+        Expr::Column(name) => {
+            if schema.contains_key(name){
+                Ok(())
+            } else {
+                Err(Error::UnknownColumn(name.to_string()))
+            }
+        },
+        Expr::Grouping(inner) => validate_expr(inner, schema),
+        Expr::IsNull{expr, ..} => validate_expr(expr, schema),
+        Expr::Unary{right, ..} => validate_expr(right, schema),
+        Expr::Binary{left, operator, right} => {
+            validate_expr(left, schema)?;
+            validate_expr(right, schema)?;
+
+            if is_comparison(operator) {
+                match (&**left, &**right) {
+                    (Expr::Column(col), Expr::Literal(val)) |
+                    (Expr::Literal(val), Expr::Column(col)) => validate_type(col, val, schema)?,
+                    _ => {},
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+fn is_comparison(operator: &TokenType) -> bool{
+    matches!(operator, TokenType::Equal | TokenType::NotEqual |
+                       TokenType::Less | TokenType::LessEqual |
+                       TokenType::Greater | TokenType::GreaterEqual)
+}
+
+fn validate_type(col: &str, val: &Literal, schema: &HashMap<String, String>) -> Result<(), Error>{
+    let col_type = match schema.get(col){
+        Some(inner_type) => inner_type,
+        None => return Ok(()),
+    };
+
+    match val {
+        Literal::String(_) if col_type != "varchar" =>
+             Err(Error::TypeMismatch("Invalid column-type combination for varchar".to_string())),
+        Literal::Number(_) if col_type != "int" =>
+             Err(Error::TypeMismatch("Invalid column-type combination for number".to_string())),
+        Literal::Boolean(_) if col_type != "bool" =>
+             Err(Error::TypeMismatch("Invalid column-type combination for bool".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Evaluates an expression against a single row.
+/// Conditions follow SQL's three-valued logic, a comparison involving NULL
+/// is neither true nor false but unknown, which is NULL as well
+pub fn evaluate(expr: &Expr, row: &Row) -> Result<Literal, Error>{
+    match expr {
+        Expr::Literal(val) => Ok(val.clone()),
+        Expr::Column(name) => match row.values.get(name){
+            Some(val) => Ok(val.clone()),
+            None => Err(Error::UnknownColumn(name.to_string())),
+        },
+        Expr::Grouping(inner) => evaluate(inner, row),
+        Expr::IsNull{expr, negated} => {
+            let is_null = evaluate(expr, row)? == Literal::Null;
+            Ok(Literal::Boolean(is_null != *negated))
+        },
+        Expr::Unary{operator: TokenType::Not, right} => not(&evaluate(right, row)?),
+        // short circuit, the right hand side is only evaluated when needed
+        Expr::Binary{left, operator: TokenType::And, right} => {
+            let lhs = evaluate(left, row)?;
+            if truth_of(&lhs)? == Some(false) {
+                return Ok(Literal::Boolean(false));
+            }
+            and(&lhs, &evaluate(right, row)?)
+        },
+        Expr::Binary{left, operator: TokenType::Or, right} => {
+            let lhs = evaluate(left, row)?;
+            if truth_of(&lhs)? == Some(true) {
+                return Ok(Literal::Boolean(true));
+            }
+            or(&lhs, &evaluate(right, row)?)
+        },
+        Expr::Binary{left, operator, right} if is_comparison(operator) =>
+            compare(operator, &evaluate(left, row)?, &evaluate(right, row)?),
+        _ => Err(Error::Execution(String::from("Unsupported operator in where clause"))),
+    }
+}
+
+/// Compares two values, the result is NULL when either of them is
+pub fn compare(operator: &TokenType, lhs: &Literal, rhs: &Literal) -> Result<Literal, Error>{
+    if *lhs == Literal::Null || *rhs == Literal::Null {
+        return Ok(Literal::Null);
+    }
+
+    let ordering = match lhs.partial_cmp(rhs){
+        Some(ordering) => ordering,
+        None => return Err(Error::TypeMismatch(String::from("Cannot compare values of different types"))),
+    };
+
+    let result = match operator {
+        TokenType::Equal => ordering == Ordering::Equal,
+        TokenType::NotEqual => ordering != Ordering::Equal,
+        TokenType::Less => ordering == Ordering::Less,
+        TokenType::LessEqual => ordering != Ordering::Greater,
+        TokenType::Greater => ordering == Ordering::Greater,
+        TokenType::GreaterEqual => ordering != Ordering::Less,
+        _ => return Err(Error::Execution(String::from("Unsupported operator in where clause"))),
+    };
+    Ok(Literal::Boolean(result))
+}
+
+/// False when either side is false, even if the other is unknown
+pub fn and(lhs: &Literal, rhs: &Literal) -> Result<Literal, Error>{
+    match (truth_of(lhs)?, truth_of(rhs)?) {
+        (Some(false), _) | (_, Some(false)) => Ok(Literal::Boolean(false)),
+        (Some(true), Some(true)) => Ok(Literal::Boolean(true)),
+        _ => Ok(Literal::Null),
+    }
+}
+
+/// True when either side is true, even if the other is unknown
+pub fn or(lhs: &Literal, rhs: &Literal) -> Result<Literal, Error>{
+    match (truth_of(lhs)?, truth_of(rhs)?) {
+        (Some(true), _) | (_, Some(true)) => Ok(Literal::Boolean(true)),
+        (Some(false), Some(false)) => Ok(Literal::Boolean(false)),
+        _ => Ok(Literal::Null),
+    }
+}
+
+/// NOT of unknown is still unknown
+pub fn not(val: &Literal) -> Result<Literal, Error>{
+    Ok(truth(truth_of(val)?.map(|val| !val)))
+}
+
+/// The truth of a condition's value, None when it is unknown
+pub fn truth_of(val: &Literal) -> Result<Option<bool>, Error>{
+    match val {
+        Literal::Boolean(val) => Ok(Some(*val)),
+        Literal::Null => Ok(None),
+        _ => Err(Error::TypeMismatch(String::from("Where clause conditions must evaluate to true or false"))),
+    }
+}
+
+fn truth(val: Option<bool>) -> Literal{
+    match val {
+        Some(val) => Literal::Boolean(val),
+        None => Literal::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter;
+
+    fn eval(condition: &str, a: i64, b: i64, c: i64) -> Literal{
+        let expr = interpreter::interpret_expression(condition).unwrap();
+        let row = Row::new(vec![String::from("a"), String::from("b"), String::from("c")],
+                           vec![Literal::Number(a), Literal::Number(b), Literal::Number(c)]);
+        evaluate(&expr, &row).unwrap()
+    }
+
+    fn is_true(condition: &str, a: i64, b: i64, c: i64) -> bool{
+        eval(condition, a, b, c) == Literal::Boolean(true)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or(){
+        let condition = "a = 1 OR b = 1 AND c = 1";
+        assert!(matches!(interpreter::interpret_expression(condition).unwrap(),
+                         Expr::Binary{operator: TokenType::Or, right, ..}
+                             if matches!(*right, Expr::Binary{operator: TokenType::And, ..})));
+
+        assert!(is_true(condition, 1, 0, 0));
+        assert!(is_true(condition, 0, 1, 1));
+        assert!(!is_true(condition, 0, 1, 0));
+        assert!(!is_true(condition, 0, 0, 1));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and(){
+        assert!(!is_true("NOT a = 1 AND b = 1", 1, 0, 0));
+        assert!(is_true("NOT a = 1 AND b = 1", 0, 1, 0));
+        assert!(is_true("NOT (a = 1 AND b = 1)", 1, 0, 0));
+    }
+
+    #[test]
+    fn not_of_a_group_negates_all_of_it(){
+        let condition = "NOT (a = 1 OR b = 1)";
+        assert!(is_true(condition, 0, 0, 0));
+        assert!(!is_true(condition, 1, 0, 0));
+        assert!(!is_true(condition, 0, 1, 0));
+        assert!(is_true("NOT NOT a = 1", 1, 0, 0));
+    }
+
+    #[test]
+    fn nested_parentheses_override_precedence(){
+        let condition = "(a = 1 OR b = 1) AND (c = 1 OR (a = 2 AND b = 2))";
+        assert!(is_true(condition, 1, 0, 1));
+        assert!(!is_true(condition, 1, 0, 0));
+        assert!(!is_true(condition, 2, 1, 0));
+        assert!(!is_true(condition, 2, 2, 0));
+        assert!(is_true("((((a = 1))))", 1, 0, 0));
+
+        // groups are kept, so the condition reads back as written
+        assert_eq!(interpreter::interpret_expression(condition).unwrap().to_string(), condition);
+    }
+
+    #[test]
+    fn every_condition_joined_by_and_has_to_hold(){
+        // conditions used to be a flat list where any one matching was enough
+        assert!(!is_true("a = 1 AND b = 1", 1, 0, 0));
+        assert!(!is_true("a = 1 AND b = 1 AND c = 1", 1, 1, 0));
+        assert!(is_true("a = 1 AND b = 1 AND c = 1", 1, 1, 1));
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_refused(){
+        for condition in ["(a = 1", "a = 1)", "(a = 1 OR b = 1))", "NOT", "a = 1 AND"] {
+            assert!(matches!(interpreter::interpret_expression(condition), Err(Error::Syntax{..})), "{}", condition);
+        }
+    }
+}
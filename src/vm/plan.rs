@@ -0,0 +1,441 @@
+use std::fmt;
+use std::ops::Bound;
+
+use crate::interpreter::stmt::Expr;
+use crate::interpreter::token::{Literal, TokenType};
+use crate::vm::rows::Rows;
+use crate::vm::table::{Index, Table};
+
+/// How a statement reads its rows. The innermost node reads the table,
+/// every other node works on the rows of the node inside it
+#[derive(Debug, Clone)]
+pub enum Plan {
+    /// Every row of the table, in id order
+    FullScan{
+        table: String,
+    },
+    /// The one row holding a primary key, when there is one
+    IdLookup{
+        table: String,
+        column: String,
+        id: i64,
+    },
+    /// The rows an index holds with its first columns equal to `equal` and
+    /// the column after them between the bounds, in the order of the index
+    /// or in reverse when `descending` is set
+    IndexRangeScan{
+        table: String,
+        index: Index,
+        equal: Vec<Literal>,
+        lower: Bound<Literal>,
+        upper: Bound<Literal>,
+        descending: bool,
+    },
+    /// The rows the predicate is true for
+    Filter{
+        input: Box<Plan>,
+        predicate: Expr,
+    },
+    /// Only the named columns of each row, in that order
+    Project{
+        input: Box<Plan>,
+        columns: Vec<String>,
+    },
+    /// The rows ordered by each column in turn, descending when its flag is set
+    Sort{
+        input: Box<Plan>,
+        keys: Vec<(String, bool)>,
+    },
+    /// No more than `count` rows
+    Limit{
+        input: Box<Plan>,
+        count: usize,
+    },
+}
+
+/// A comparison of a column to a value, written with the column first
+struct Term<'e> {
+    column: &'e str,
+    operator: &'e TokenType,
+    value: &'e Literal,
+}
+
+impl Plan {
+    /// The node this one reads its rows from, None for those reading the table
+    pub fn input(&self) -> Option<&Plan>{
+        match self {
+            Plan::Filter{input, ..} | Plan::Project{input, ..} |
+            Plan::Sort{input, ..} | Plan::Limit{input, ..} => Some(input),
+            _ => None,
+        }
+    }
+
+    /// One row per node, from the outermost in, with the id of the node
+    /// and that of the node it hands its rows to, 0 for the outermost one
+    pub fn explain(&self) -> Rows{
+        let mut rows = Rows::new(
+            vec![String::from("id"), String::from("parent"), String::from("detail")],
+            vec![String::from("int"), String::from("int"), String::from("varchar")]);
+
+        let mut node = Some(self);
+        let mut parent = 0;
+        while let Some(plan) = node {
+            let id = parent + 1;
+            rows.rows.push(vec![Literal::Number(id), Literal::Number(parent), Literal::String(plan.to_string())]);
+            parent = id;
+            node = plan.input();
+        }
+        rows
+    }
+}
+
+/// Plans how to find the rows a where clause is true for. The where clause
+/// is always applied to the rows read, as a lookup may read more than it needs
+pub fn access(table: &Table, where_clause: Option<&Expr>) -> Plan{
+    match where_clause {
+        Some(predicate) => Plan::Filter{
+            input: Box::new(source(table, predicate)),
+            predicate: predicate.clone(),
+        },
+        None => Plan::FullScan{ table: table.name.clone() },
+    }
+}
+
+pub fn select(table: &Table,
+              columns: &[String],
+              where_clause: Option<&Expr>,
+              order_by: &[(String, bool)],
+              limit: Option<usize>) -> Plan{
+    let plan = Plan::Project{
+        input: Box::new(order(table, access(table, where_clause), order_by)),
+        columns: columns.to_vec(),
+    };
+
+    match limit {
+        Some(count) => Plan::Limit{ input: Box::new(plan), count },
+        None => plan,
+    }
+}
+
+/// Puts the rows of an access plan in order. Nothing has to be sorted when
+/// the rows are read in that order already, which is also the case when
+/// every row is read through an index on the columns sorted by
+fn order(table: &Table, plan: Plan, keys: &[(String, bool)]) -> Plan{
+    if keys.is_empty() {
+        return plan;
+    }
+
+    let (predicate, mut source) = match plan {
+        Plan::Filter{input, predicate} => (Some(predicate), *input),
+        source => (None, source),
+    };
+
+    let by_index = table.indexes.iter()
+        .find_map(|index| sorted_by(index, &[], keys).map(|descending| (index, descending)));
+    if let (Plan::FullScan{..}, Some((index, descending))) = (&source, by_index) {
+        if !is_sorted(table, &source, keys) {
+            source = Plan::IndexRangeScan{
+                table: table.name.clone(),
+                index: index.clone(),
+                equal: Vec::new(),
+                lower: Bound::Unbounded,
+                upper: Bound::Unbounded,
+                descending,
+            };
+        }
+    }
+    if let Plan::IndexRangeScan{index, equal, descending, ..} = &mut source {
+        if let Some(reverse) = sorted_by(index, equal, keys) {
+            *descending = reverse;
+        }
+    }
+
+    let sorted = is_sorted(table, &source, keys);
+    let plan = match predicate {
+        Some(predicate) => Plan::Filter{ input: Box::new(source), predicate },
+        None => source,
+    };
+
+    match sorted {
+        true => plan,
+        false => Plan::Sort{ input: Box::new(plan), keys: keys.to_vec() },
+    }
+}
+
+/// True when the rows a source reads come out in the order of the keys:
+/// a single row, every row in order of the primary key, or an index read
+/// in the direction the keys ask for
+fn is_sorted(table: &Table, source: &Plan, keys: &[(String, bool)]) -> bool{
+    match source {
+        Plan::IdLookup{..} => true,
+        Plan::FullScan{..} => matches!(keys.first(), Some((column, false)) if *column == table.primary_key().name),
+        Plan::IndexRangeScan{index, equal, descending, ..} => sorted_by(index, equal, keys) == Some(*descending),
+        _ => false,
+    }
+}
+
+/// Whether reading an index with its first columns held at `equal` gives the
+/// rows in the order of the keys, forwards (Some(false)) or backwards
+/// (Some(true)). The columns held equal can be left out of the keys, the
+/// others have to be the next columns of the index, all sorted the same way
+fn sorted_by(index: &Index, equal: &[Literal], keys: &[(String, bool)]) -> Option<bool>{
+    let held = &index.columns[..equal.len().min(index.columns.len())];
+    let keys: Vec<&(String, bool)> = keys.iter().filter(|(column, _)| !held.contains(column)).collect();
+
+    let Some((_, descending)) = keys.first() else {
+        return Some(false);
+    };
+    let in_order = keys.len() <= index.columns.len() - held.len() &&
+        keys.iter().zip(&index.columns[held.len()..])
+            .all(|((column, direction), indexed)| column == indexed && direction == descending);
+
+    in_order.then_some(*descending)
+}
+
+/// Picks the cheapest way in: the row a primary key is compared to, then the
+/// index whose first columns the most comparisons with `=` cover, counting a
+/// range on the column after them as half a column. Every row is read when
+/// no comparison helps
+fn source(table: &Table, predicate: &Expr) -> Plan{
+    let mut terms: Vec<Term> = Vec::new();
+    comparisons(predicate, &mut terms);
+
+    let equal = |column: &str| terms.iter()
+        .find(|term| term.column == column && matches!(term.operator, TokenType::Equal))
+        .map(|term| term.value);
+
+    let primary_key = &table.primary_key().name;
+    if let Some(Literal::Number(id)) = equal(primary_key) {
+        return Plan::IdLookup{
+            table: table.name.clone(),
+            column: primary_key.clone(),
+            id: *id,
+        };
+    }
+
+    let mut best: Option<(&Index, usize, bool)> = None;
+    for index in &table.indexes {
+        let covered = index.columns.iter().take_while(|col| equal(col).is_some()).count();
+        let ranged = index.columns.get(covered)
+            .is_some_and(|col| terms.iter().any(|term| term.column == col && is_range(term.operator)));
+
+        let score = covered * 2 + ranged as usize;
+        if score > 0 && best.is_none_or(|(_, covered, ranged)| score > covered * 2 + ranged as usize) {
+            best = Some((index, covered, ranged));
+        }
+    }
+
+    let Some((index, covered, ranged)) = best else {
+        return Plan::FullScan{ table: table.name.clone() };
+    };
+
+    let equal: Vec<Literal> = index.columns[..covered].iter()
+        .filter_map(|col| equal(col).cloned())
+        .collect();
+
+    let mut lower = Bound::Unbounded;
+    let mut upper = Bound::Unbounded;
+    if ranged {
+        for term in terms.iter().filter(|term| term.column == index.columns[covered]) {
+            match term.operator {
+                TokenType::Greater if lower == Bound::Unbounded => lower = Bound::Excluded(term.value.clone()),
+                TokenType::GreaterEqual if lower == Bound::Unbounded => lower = Bound::Included(term.value.clone()),
+                TokenType::Less if upper == Bound::Unbounded => upper = Bound::Excluded(term.value.clone()),
+                TokenType::LessEqual if upper == Bound::Unbounded => upper = Bound::Included(term.value.clone()),
+                _ => {},
+            }
+        }
+    }
+
+    Plan::IndexRangeScan{
+        table: table.name.clone(),
+        index: index.clone(),
+        equal,
+        lower,
+        upper,
+        descending: false,
+    }
+}
+
+fn is_range(operator: &TokenType) -> bool{
+    matches!(operator, TokenType::Less | TokenType::LessEqual | TokenType::Greater | TokenType::GreaterEqual)
+}
+
+/// The comparisons of a column to a value that every matching row has to meet,
+/// those joined by AND. A comparison with NULL is never true and is left out
+fn comparisons<'e>(expr: &'e Expr, terms: &mut Vec<Term<'e>>){
+    match expr {
+        Expr::Binary{left, operator: TokenType::And, right} => {
+            comparisons(left, terms);
+            comparisons(right, terms);
+        },
+        Expr::Grouping(inner) => comparisons(inner, terms),
+        Expr::Binary{left, operator, right} => {
+            let term = match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(column), Expr::Literal(value)) => Term{ column, operator, value },
+                (Expr::Literal(value), Expr::Column(column)) => Term{ column, operator: flipped(operator), value },
+                _ => return,
+            };
+            if *term.value != Literal::Null && (matches!(term.operator, TokenType::Equal) || is_range(term.operator)) {
+                terms.push(term);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// The operator that keeps the comparison true once its sides are swapped
+fn flipped(operator: &TokenType) -> &'static TokenType{
+    match operator {
+        TokenType::Less => &TokenType::Greater,
+        TokenType::LessEqual => &TokenType::GreaterEqual,
+        TokenType::Greater => &TokenType::Less,
+        TokenType::GreaterEqual => &TokenType::LessEqual,
+        TokenType::Equal => &TokenType::Equal,
+        _ => &TokenType::NotEqual,
+    }
+}
+
+/// The values an index seek is limited to, as in `a=1 AND b>2`
+pub fn bounds(index: &Index, equal: &[Literal], lower: &Bound<Literal>, upper: &Bound<Literal>) -> String{
+    let mut terms: Vec<String> = index.columns.iter().zip(equal)
+        .map(|(column, value)| format!("{}={}", column, value))
+        .collect();
+
+    if let Some(column) = index.columns.get(equal.len()) {
+        match lower {
+            Bound::Included(value) => terms.push(format!("{}>={}", column, value)),
+            Bound::Excluded(value) => terms.push(format!("{}>{}", column, value)),
+            Bound::Unbounded => {},
+        }
+        match upper {
+            Bound::Included(value) => terms.push(format!("{}<={}", column, value)),
+            Bound::Excluded(value) => terms.push(format!("{}<{}", column, value)),
+            Bound::Unbounded => {},
+        }
+    }
+    terms.join(" AND ")
+}
+
+/// The detail of a single node, as EXPLAIN shows it
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self {
+            Plan::FullScan{table} => write!(f, "SCAN {}", table),
+            Plan::IdLookup{table, column, id} => write!(f, "SEARCH {} USING PRIMARY KEY ({}={})", table, column, id),
+            Plan::IndexRangeScan{table, index, equal, lower, upper, descending} => {
+                let bounds = bounds(index, equal, lower, upper);
+                match bounds.is_empty() {
+                    true => write!(f, "SCAN {} USING INDEX {}", table, index.name)?,
+                    false => write!(f, "SEARCH {} USING INDEX {} ({})", table, index.name, bounds)?,
+                }
+                if *descending {
+                    write!(f, " DESC")?;
+                }
+                Ok(())
+            },
+            Plan::Filter{predicate, ..} => write!(f, "FILTER {}", predicate),
+            Plan::Project{columns, ..} => write!(f, "PROJECT {}", columns.join(", ")),
+            Plan::Sort{keys, ..} => {
+                let keys: Vec<String> = keys.iter()
+                    .map(|(column, descending)| if *descending { format!("{} DESC", column) } else { column.clone() })
+                    .collect();
+                write!(f, "SORT BY {}", keys.join(", "))
+            },
+            Plan::Limit{count, ..} => write!(f, "LIMIT {}", count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test_util::{people, query_plan, where_clause};
+    use crate::{Connection, Error};
+
+    /// The node that reads the table, under the filter
+    fn source_of(condition: &str) -> Plan{
+        match access(&people(), Some(&where_clause(condition))) {
+            Plan::Filter{input, ..} => *input,
+            plan => panic!("expected a filter, planned {:?}", plan),
+        }
+    }
+
+    #[test]
+    fn id_equality_looks_up_the_row(){
+        for condition in ["id = 3", "3 = id", "age > 20 AND id = 3", "(city = 'oslo' AND id = 3)"] {
+            assert!(matches!(source_of(condition), Plan::IdLookup{id: 3, ..}), "{}", condition);
+        }
+        // a range on the primary key has no lookup of its own
+        assert!(matches!(source_of("id > 3"), Plan::FullScan{..}));
+    }
+
+    #[test]
+    fn indexed_range_searches_the_index(){
+        let Plan::IndexRangeScan{index, equal, lower, upper, descending, ..} = source_of("age > 20 AND age <= 40") else {
+            panic!("expected an index scan");
+        };
+        assert_eq!(index.name, "byage");
+        assert!(equal.is_empty());
+        assert_eq!(lower, Bound::Excluded(Literal::Number(20)));
+        assert_eq!(upper, Bound::Included(Literal::Number(40)));
+        assert!(!descending);
+
+        // flipped comparisons bound the same side
+        let Plan::IndexRangeScan{lower, upper, ..} = source_of("40 > age") else {
+            panic!("expected an index scan");
+        };
+        assert_eq!(lower, Bound::Unbounded);
+        assert_eq!(upper, Bound::Excluded(Literal::Number(40)));
+    }
+
+    #[test]
+    fn equality_on_more_columns_picks_the_wider_index(){
+        let Plan::IndexRangeScan{index, equal, upper, ..} = source_of("age < 30 AND city = 'oslo'") else {
+            panic!("expected an index scan");
+        };
+        assert_eq!(index.name, "bycity");
+        assert_eq!(equal, [Literal::String(String::from("oslo"))]);
+        assert_eq!(upper, Bound::Excluded(Literal::Number(30)));
+
+        let Plan::IndexRangeScan{index, equal, ..} = source_of("age = 30 AND name = 'bob'") else {
+            panic!("expected an index scan");
+        };
+        assert_eq!(index.name, "byage");
+        assert_eq!(equal, [Literal::Number(30)]);
+    }
+
+    #[test]
+    fn unindexed_predicates_scan_the_table(){
+        for condition in ["name = 'bob'", "age <> 3", "age = NULL", "age > 20 OR id = 3", "NOT age = 3"] {
+            assert!(matches!(source_of(condition), Plan::FullScan{..}), "{}", condition);
+        }
+        assert!(matches!(access(&people(), None), Plan::FullScan{..}));
+    }
+
+    #[test]
+    fn explain_query_plan_shows_each_node() -> Result<(), Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE people (name varchar, age int, city varchar);
+                      CREATE INDEX byage ON people (age);")?;
+
+        let node = |id, parent, detail: &str| (id, parent, detail.to_string());
+        assert_eq!(query_plan(&mut conn, "SELECT name FROM people WHERE age > 20 AND age <= 40;")?, [
+            node(1, 0, "PROJECT name"),
+            node(2, 1, "FILTER age > 20 AND age <= 40"),
+            node(3, 2, "SEARCH people USING INDEX byage (age>20 AND age<=40)"),
+        ]);
+        assert_eq!(query_plan(&mut conn, "SELECT * FROM people WHERE name = 'bob' LIMIT 2;")?, [
+            node(1, 0, "LIMIT 2"),
+            node(2, 1, "PROJECT id, name, age, city"),
+            node(3, 2, "FILTER name = 'bob'"),
+            node(4, 3, "SCAN people"),
+        ]);
+        assert_eq!(query_plan(&mut conn, "DELETE FROM people WHERE id = 2;")?, [
+            node(1, 0, "FILTER id = 2"),
+            node(2, 1, "SEARCH people USING PRIMARY KEY (id=2)"),
+        ]);
+        assert!(conn.query("EXPLAIN QUERY PLAN INSERT INTO people (age) VALUES (1);").is_err());
+        Ok(())
+    }
+}
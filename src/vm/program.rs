@@ -0,0 +1,315 @@
+use std::ops::Bound;
+
+use crate::interpreter::token::{Literal, TokenType};
+use crate::vm::plan::bounds;
+use crate::vm::rows::Rows;
+use crate::vm::table::{Index, Table};
+
+/// Position of an op within its program
+pub type Address = usize;
+
+/// Numbered slot holding a single value while a program runs
+pub type Register = usize;
+
+/// Numbered slot holding an open cursor, or a set of row ids
+pub type Slot = usize;
+
+/// A single instruction. Ops that can move somewhere else than to the next
+/// op name the address they jump to as `target`
+#[derive(Debug, Clone)]
+pub enum Op {
+    /// Opens a cursor on a table, it points at no row until it is moved
+    OpenTable{
+        cursor: Slot,
+        table: Table,
+    },
+    /// Moves to the first row of the table, or jumps when it is empty
+    Rewind{
+        cursor: Slot,
+        target: Address,
+    },
+    /// Moves to the row whose id is in a register, or jumps when there is none
+    SeekRowid{
+        cursor: Slot,
+        id: Register,
+        target: Address,
+    },
+    /// Moves to the first of the rows an index holds with its first columns
+    /// equal to `equal` and the next one within the bounds, or jumps when
+    /// there are none. The cursor then goes through them in index order,
+    /// or in reverse when `descending` is set
+    SeekIndex{
+        cursor: Slot,
+        index: Index,
+        equal: Vec<Literal>,
+        lower: Bound<Literal>,
+        upper: Bound<Literal>,
+        descending: bool,
+        target: Address,
+    },
+    /// Moves to the next row and jumps back to handle it, falls through at the end
+    Next{
+        cursor: Slot,
+        target: Address,
+    },
+    /// Reads a column of the row the cursor points at
+    Column{
+        cursor: Slot,
+        column: String,
+        dest: Register,
+    },
+    /// Reads the id of the row the cursor points at
+    Rowid{
+        cursor: Slot,
+        dest: Register,
+    },
+    Value{
+        value: Literal,
+        dest: Register,
+    },
+    /// true or false, or NULL when either value is NULL
+    Compare{
+        operator: TokenType,
+        lhs: Register,
+        rhs: Register,
+        dest: Register,
+    },
+    And{
+        lhs: Register,
+        rhs: Register,
+        dest: Register,
+    },
+    Or{
+        lhs: Register,
+        rhs: Register,
+        dest: Register,
+    },
+    Not{
+        src: Register,
+        dest: Register,
+    },
+    IsNull{
+        src: Register,
+        negated: bool,
+        dest: Register,
+    },
+    /// Jumps unless the register holds true, so on false and on NULL
+    IfNot{
+        src: Register,
+        target: Address,
+    },
+    /// Jumps when the register holds false
+    IfFalse{
+        src: Register,
+        target: Address,
+    },
+    /// Jumps when the register holds true
+    IfTrue{
+        src: Register,
+        target: Address,
+    },
+    Goto{
+        target: Address,
+    },
+    /// Adds the values of `count` registers from `start` on to the result
+    ResultRow{
+        start: Register,
+        count: usize,
+    },
+    /// Takes one off the number in a register and jumps once it reaches zero
+    DecrJumpZero{
+        src: Register,
+        target: Address,
+    },
+    /// Opens a cursor that collects rows and hands them back sorted
+    SorterOpen{
+        cursor: Slot,
+        keys: Vec<(String, bool)>,
+    },
+    /// Copies the row another cursor points at into a sorter
+    SorterInsert{
+        cursor: Slot,
+        from: Slot,
+    },
+    /// Sorts the rows collected and moves to the first, or jumps when there are none
+    SorterSort{
+        cursor: Slot,
+        target: Address,
+    },
+    /// Adds the id in a register to a set of ids, kept in the order they are added
+    RowSetAdd{
+        rowset: Slot,
+        src: Register,
+    },
+    /// Takes the next id out of a set, or jumps once it is empty
+    RowSetRead{
+        rowset: Slot,
+        dest: Register,
+        target: Address,
+    },
+    /// Adds a row holding the values of the registers from `start` on in the
+    /// named columns to the cursor's table, with every constraint checked
+    Insert{
+        cursor: Slot,
+        columns: Vec<String>,
+        start: Register,
+    },
+    /// Sets the named columns of the row with the id in a register to the
+    /// values of the registers from `start` on
+    Update{
+        cursor: Slot,
+        id: Register,
+        columns: Vec<String>,
+        start: Register,
+    },
+    /// Deletes the row with the id in a register, along with
+    /// whatever its foreign keys delete with it
+    Delete{
+        cursor: Slot,
+        id: Register,
+    },
+    Halt,
+}
+
+/// A compiled statement, run from its first op until it halts
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    /// Names and types of the values each ResultRow hands back
+    pub columns: Vec<String>,
+    pub types: Vec<String>,
+    /// Number of registers the program uses
+    pub registers: usize,
+    /// Number of cursor and row set slots the program uses
+    pub slots: usize,
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str{
+        match self {
+            Op::OpenTable{..} => "OpenTable",
+            Op::Rewind{..} => "Rewind",
+            Op::SeekRowid{..} => "SeekRowid",
+            Op::SeekIndex{..} => "SeekIndex",
+            Op::Next{..} => "Next",
+            Op::Column{..} => "Column",
+            Op::Rowid{..} => "Rowid",
+            Op::Value{..} => "Value",
+            Op::Compare{..} => "Compare",
+            Op::And{..} => "And",
+            Op::Or{..} => "Or",
+            Op::Not{..} => "Not",
+            Op::IsNull{..} => "IsNull",
+            Op::IfNot{..} => "IfNot",
+            Op::IfFalse{..} => "IfFalse",
+            Op::IfTrue{..} => "IfTrue",
+            Op::Goto{..} => "Goto",
+            Op::ResultRow{..} => "ResultRow",
+            Op::DecrJumpZero{..} => "DecrJumpZero",
+            Op::SorterOpen{..} => "SorterOpen",
+            Op::SorterInsert{..} => "SorterInsert",
+            Op::SorterSort{..} => "SorterSort",
+            Op::RowSetAdd{..} => "RowSetAdd",
+            Op::RowSetRead{..} => "RowSetRead",
+            Op::Insert{..} => "Insert",
+            Op::Update{..} => "Update",
+            Op::Delete{..} => "Delete",
+            Op::Halt => "Halt",
+        }
+    }
+
+    /// The address the op may jump to
+    pub fn target_mut(&mut self) -> Option<&mut Address>{
+        match self {
+            Op::Rewind{target, ..} | Op::SeekRowid{target, ..} | Op::SeekIndex{target, ..} |
+            Op::Next{target, ..} | Op::IfNot{target, ..} | Op::IfFalse{target, ..} |
+            Op::IfTrue{target, ..} | Op::Goto{target} | Op::DecrJumpZero{target, ..} |
+            Op::SorterSort{target, ..} | Op::RowSetRead{target, ..} => Some(target),
+            _ => None,
+        }
+    }
+
+    /// The operands as EXPLAIN lists them: up to three numbers, being
+    /// slots, registers and jump targets, and a description of the rest
+    pub fn operands(&self) -> (Option<usize>, Option<usize>, Option<usize>, Option<String>){
+        match self {
+            Op::OpenTable{cursor, table} => (Some(*cursor), None, None, Some(table.name.clone())),
+            Op::Rewind{cursor, target} | Op::Next{cursor, target} | Op::SorterSort{cursor, target} =>
+                (Some(*cursor), Some(*target), None, None),
+            Op::SeekRowid{cursor, id, target} => (Some(*cursor), Some(*target), Some(*id), None),
+            Op::SeekIndex{cursor, index, equal, lower, upper, descending, target} => {
+                let bounds = bounds(index, equal, lower, upper);
+                let mut detail = index.name.clone();
+                if !bounds.is_empty() {
+                    detail.push_str(&format!(" ({})", bounds));
+                }
+                if *descending {
+                    detail.push_str(" DESC");
+                }
+                (Some(*cursor), Some(*target), None, Some(detail))
+            },
+            Op::Column{cursor, column, dest} => (Some(*cursor), None, Some(*dest), Some(column.clone())),
+            Op::Rowid{cursor, dest} => (Some(*cursor), Some(*dest), None, None),
+            Op::Value{value, dest} => (None, Some(*dest), None, Some(value.to_string())),
+            Op::Compare{operator, lhs, rhs, dest} =>
+                (Some(*lhs), Some(*rhs), Some(*dest), Some(symbol(operator).to_string())),
+            Op::And{lhs, rhs, dest} | Op::Or{lhs, rhs, dest} => (Some(*lhs), Some(*rhs), Some(*dest), None),
+            Op::Not{src, dest} => (Some(*src), Some(*dest), None, None),
+            Op::IsNull{src, negated, dest} =>
+                (Some(*src), Some(*dest), None, Some(String::from(if *negated { "IS NOT NULL" } else { "IS NULL" }))),
+            Op::IfNot{src, target} | Op::IfFalse{src, target} | Op::IfTrue{src, target} |
+            Op::DecrJumpZero{src, target} => (Some(*src), Some(*target), None, None),
+            Op::Goto{target} => (None, Some(*target), None, None),
+            Op::ResultRow{start, count} => (Some(*start), Some(*count), None, None),
+            Op::SorterOpen{cursor, keys} => {
+                let keys: Vec<String> = keys.iter()
+                    .map(|(column, descending)| if *descending { format!("{} DESC", column) } else { column.clone() })
+                    .collect();
+                (Some(*cursor), None, None, Some(keys.join(", ")))
+            },
+            Op::SorterInsert{cursor, from} => (Some(*cursor), Some(*from), None, None),
+            Op::RowSetAdd{rowset, src} => (Some(*rowset), Some(*src), None, None),
+            Op::RowSetRead{rowset, dest, target} => (Some(*rowset), Some(*target), Some(*dest), None),
+            Op::Insert{cursor, columns, start} => (Some(*cursor), Some(*start), None, Some(columns.join(", "))),
+            Op::Update{cursor, id, columns, start} => (Some(*cursor), Some(*start), Some(*id), Some(columns.join(", "))),
+            Op::Delete{cursor, id} => (Some(*cursor), Some(*id), None, None),
+            Op::Halt => (None, None, None, None),
+        }
+    }
+}
+
+impl Program {
+    /// One row per op, with its address, name and operands
+    pub fn explain(&self) -> Rows{
+        let columns = ["addr", "opcode", "p1", "p2", "p3", "p4"];
+        let types = ["int", "varchar", "int", "int", "int", "varchar"];
+        let mut rows = Rows::new(
+            columns.iter().map(|col| col.to_string()).collect(),
+            types.iter().map(|datatype| datatype.to_string()).collect());
+
+        let number = |operand: Option<usize>| operand.map_or(Literal::Null, |val| Literal::Number(val as i64));
+        for (addr, op) in self.ops.iter().enumerate() {
+            let (p1, p2, p3, p4) = op.operands();
+            rows.rows.push(vec![
+                Literal::Number(addr as i64),
+                Literal::String(op.name().to_string()),
+                number(p1),
+                number(p2),
+                number(p3),
+                p4.map_or(Literal::Null, Literal::String),
+            ]);
+        }
+        rows
+    }
+}
+
+fn symbol(operator: &TokenType) -> &'static str{
+    match operator {
+        TokenType::Equal => "=",
+        TokenType::NotEqual => "<>",
+        TokenType::Less => "<",
+        TokenType::LessEqual => "<=",
+        TokenType::Greater => ">",
+        _ => ">=",
+    }
+}
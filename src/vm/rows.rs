@@ -0,0 +1,63 @@
+use crate::interpreter::token::Literal;
+
+/// The result of a SELECT statement. Each row holds one value per
+/// selected column, in the same order as `columns`, and `types` holds
+/// the declared datatype of each column (`int`, `varchar` or `bool`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rows {
+    pub columns: Vec<String>,
+    pub types: Vec<String>,
+    pub rows: Vec<Vec<Literal>>,
+}
+
+impl Rows {
+    pub fn new(columns: Vec<String>, types: Vec<String>) -> Self{
+        Rows {
+            columns,
+            types,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.rows.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Vec<Literal>>{
+        self.rows.iter()
+    }
+
+    /// The declared datatype of a column
+    pub fn column_type(&self, column: &str) -> Option<&str>{
+        let idx = self.columns.iter().position(|name| name == column)?;
+        self.types.get(idx).map(String::as_str)
+    }
+
+    /// Looks up a single value by row index and column name
+    pub fn get(&self, row: usize, column: &str) -> Option<&Literal>{
+        let idx = self.columns.iter().position(|name| name == column)?;
+        self.rows.get(row)?.get(idx)
+    }
+}
+
+impl IntoIterator for Rows {
+    type Item = Vec<Literal>;
+    type IntoIter = std::vec::IntoIter<Vec<Literal>>;
+
+    fn into_iter(self) -> Self::IntoIter{
+        self.rows.into_iter()
+    }
+}
+
+impl<'r> IntoIterator for &'r Rows {
+    type Item = &'r Vec<Literal>;
+    type IntoIter = std::slice::Iter<'r, Vec<Literal>>;
+
+    fn into_iter(self) -> Self::IntoIter{
+        self.rows.iter()
+    }
+}
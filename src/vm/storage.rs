@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use std::slice;
+
+use crate::error::Error;
+use crate::storage::btree::{self, Cursor};
+use crate::storage::pager::{Pager, PageNo};
+use crate::interpreter::token::Literal;
+use crate::vm::table::{Table, Row, Index};
+use crate::vm::virtualmachine::Database;
+
+/// Position within the rows of a table
+pub struct RowCursor(Cursor);
+
+/// Opening this path keeps the database in memory for the
+/// lifetime of the VM instead of writing it to a file
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// Page 1 is the root of the catalog, a B-tree that maps
+/// every table name to its definition
+const CATALOG_ROOT: PageNo = 1;
+
+/// A database made up of pages, either in a file on disk or in
+/// memory. Every table keeps its rows in a B-tree keyed by row id,
+/// so changing a row only rewrites the pages it lives on
+pub struct Storage {
+    pager: Pager,
+}
+
+impl Storage {
+    /// Opens the database at `path`, creating the file and any missing
+    /// parent directories when it does not exist yet
+    pub fn open(path: &Path) -> Result<Storage, Error>{
+        let mut pager = if path == Path::new(MEMORY_PATH) {
+            Pager::memory()
+        } else {
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            Pager::open(path)?
+        };
+
+        if pager.is_new() {
+            let root = btree::create(&mut pager)?;
+            debug_assert_eq!(root, CATALOG_ROOT);
+            pager.commit()?;
+        }
+
+        Ok(Storage{
+            pager,
+        })
+    }
+
+    /// Every table definition, without their rows
+    pub fn tables(&mut self) -> Result<Database, Error>{
+        let mut tables = BTreeMap::new();
+        let mut cursor = Cursor::first(&mut self.pager, CATALOG_ROOT)?;
+
+        while let Some((_, value)) = cursor.next(&mut self.pager)? {
+            let table: Table = decode(&value)?;
+            tables.insert(table.name.clone(), table);
+        }
+        Ok(Database{
+            tables,
+        })
+    }
+
+    pub fn table(&mut self, name: &str) -> Result<Option<Table>, Error>{
+        match btree::get(&mut self.pager, CATALOG_ROOT, name.as_bytes())? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gives the table its own B-tree and adds it to the catalog
+    pub fn create_table(&mut self, mut table: Table) -> Result<(), Error>{
+        table.root = btree::create(&mut self.pager)?;
+        self.save_table(&table)
+    }
+
+    /// Writes a changed table definition back to the catalog
+    pub fn save_table(&mut self, table: &Table) -> Result<(), Error>{
+        btree::insert(&mut self.pager, CATALOG_ROOT, table.name.as_bytes(), &encode(table)?)
+    }
+
+    /// Removes the table from the catalog and frees all of its pages,
+    /// along with those of its indexes
+    pub fn drop_table(&mut self, table: &Table) -> Result<(), Error>{
+        for index in &table.indexes {
+            btree::destroy(&mut self.pager, index.root)?;
+        }
+        btree::destroy(&mut self.pager, table.root)?;
+        btree::delete(&mut self.pager, CATALOG_ROOT, table.name.as_bytes())?;
+        Ok(())
+    }
+
+    /// Visits every row of the table in id order
+    pub fn scan(&mut self,
+                table: &Table,
+                mut visit: impl FnMut(i64, Row) -> Result<(), Error>) -> Result<(), Error>{
+        let mut cursor = Cursor::first(&mut self.pager, table.root)?;
+
+        while let Some((key, value)) = cursor.next(&mut self.pager)? {
+            visit(decode_id(&key)?, decode(&value)?)?;
+        }
+        Ok(())
+    }
+
+    /// A cursor before the first row of the table, see `next_row`
+    pub fn rows(&mut self, table: &Table) -> Result<RowCursor, Error>{
+        Ok(RowCursor(Cursor::first(&mut self.pager, table.root)?))
+    }
+
+    /// The row after the one the cursor was on, in id order. The table
+    /// must not change while the cursor is in use
+    pub fn next_row(&mut self, cursor: &mut RowCursor) -> Result<Option<(i64, Row)>, Error>{
+        match cursor.0.next(&mut self.pager)? {
+            Some((key, value)) => Ok(Some((decode_id(&key)?, decode(&value)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The largest id in the table, None when it is empty
+    pub fn last_id(&mut self, table: &Table) -> Result<Option<i64>, Error>{
+        match btree::last(&mut self.pager, table.root)? {
+            Some(key) => Ok(Some(decode_id(&key)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn row(&mut self, table: &Table, id: i64) -> Result<Option<Row>, Error>{
+        match btree::get(&mut self.pager, table.root, &encode_id(id))? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts the row, or replaces the one already stored under `id`
+    pub fn put_row(&mut self, table: &Table, id: i64, row: &Row) -> Result<(), Error>{
+        btree::insert(&mut self.pager, table.root, &encode_id(id), &encode(row)?)
+    }
+
+    pub fn delete_row(&mut self, table: &Table, id: i64) -> Result<bool, Error>{
+        btree::delete(&mut self.pager, table.root, &encode_id(id))
+    }
+
+    /// Gives the index its own, empty, B-tree
+    pub fn create_index(&mut self, index: &mut Index) -> Result<(), Error>{
+        index.root = btree::create(&mut self.pager)?;
+        Ok(())
+    }
+
+    pub fn index_add(&mut self, index: &Index, values: &[Literal], id: i64) -> Result<(), Error>{
+        let mut key = encode_values(values);
+        key.extend_from_slice(&encode_id(id));
+        btree::insert(&mut self.pager, index.root, &key, &[])
+    }
+
+    pub fn index_remove(&mut self, index: &Index, values: &[Literal], id: i64) -> Result<bool, Error>{
+        let mut key = encode_values(values);
+        key.extend_from_slice(&encode_id(id));
+        btree::delete(&mut self.pager, index.root, &key)
+    }
+
+    /// Frees every page of the index
+    pub fn drop_index(&mut self, index: &Index) -> Result<(), Error>{
+        btree::destroy(&mut self.pager, index.root)
+    }
+
+    /// Ids of the rows whose indexed columns hold exactly `values`. Fewer values
+    /// than columns match on the first columns only, with the ids then in the
+    /// order of the remaining columns
+    pub fn index_lookup(&mut self, index: &Index, values: &[Literal]) -> Result<Vec<i64>, Error>{
+        self.index_range(index, values, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Ids of the rows whose first indexed columns hold `values` and whose
+    /// next column lies between the bounds, in the order of the index. Only
+    /// the keys within the bounds are read
+    pub fn index_range(&mut self,
+                       index: &Index,
+                       values: &[Literal],
+                       lower: Bound<&Literal>,
+                       upper: Bound<&Literal>) -> Result<Vec<i64>, Error>{
+        let prefix = encode_values(values);
+        let bound = |value: &Literal| {
+            let mut key = prefix.clone();
+            key.extend(encode_values(slice::from_ref(value)));
+            key
+        };
+
+        let start = match lower {
+            Bound::Included(value) | Bound::Excluded(value) => bound(value),
+            Bound::Unbounded => prefix.clone(),
+        };
+        let end = match upper {
+            Bound::Included(value) | Bound::Excluded(value) => Some(bound(value)),
+            Bound::Unbounded => None,
+        };
+
+        let mut cursor = Cursor::seek(&mut self.pager, index.root, &start)?;
+        let mut ids = Vec::new();
+
+        while let Some((key, _)) = cursor.next(&mut self.pager)? {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if matches!(lower, Bound::Excluded(_)) && key.starts_with(&start) {
+                continue;
+            }
+            if let Some(end) = &end {
+                let at_end = key.starts_with(end);
+                if (at_end && matches!(upper, Bound::Excluded(_))) || (!at_end && key.as_slice() > end.as_slice()) {
+                    break;
+                }
+            }
+            // every key ends in the id of its row
+            ids.push(decode_id(&key[key.len().saturating_sub(8)..])?);
+        }
+        Ok(ids)
+    }
+
+    /// Makes every change since the last commit permanent
+    pub fn commit(&mut self) -> Result<(), Error>{
+        self.pager.commit()
+    }
+
+    /// Throws away every change since the last commit
+    pub fn rollback(&mut self){
+        self.pager.rollback();
+    }
+
+    /// True when there are changes waiting to be committed
+    pub fn has_changes(&self) -> bool{
+        self.pager.is_dirty()
+    }
+
+    pub fn begin_savepoint(&mut self){
+        self.pager.begin_savepoint();
+    }
+
+    pub fn release_savepoint(&mut self){
+        self.pager.release_savepoint();
+    }
+
+    pub fn rollback_savepoint(&mut self){
+        self.pager.rollback_savepoint();
+    }
+}
+
+/// Flipping the sign bit makes the big endian bytes of
+/// an id sort the same way as the id itself
+fn encode_id(id: i64) -> [u8; 8]{
+    ((id as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn decode_id(key: &[u8]) -> Result<i64, Error>{
+    let bytes: [u8; 8] = key.try_into()
+        .map_err(|_| Error::Corruption(String::from("row id has the wrong length")))?;
+    Ok((u64::from_be_bytes(bytes) ^ (1 << 63)) as i64)
+}
+
+/// Index keys sort the way the values do, column by column: NULL first,
+/// then bools, numbers and strings. Strings end in 0x00 0x01 with any
+/// 0x00 inside them written as 0x00 0xff, so a string sorts before
+/// every longer string it is the start of
+fn encode_values(values: &[Literal]) -> Vec<u8>{
+    let mut key = Vec::new();
+
+    for value in values {
+        match value {
+            Literal::Boolean(val) => {
+                key.push(1);
+                key.push(*val as u8);
+            },
+            Literal::Number(val) => {
+                key.push(2);
+                key.extend_from_slice(&encode_id(*val));
+            },
+            Literal::String(val) => {
+                key.push(3);
+                for byte in val.bytes() {
+                    key.push(byte);
+                    if byte == 0 {
+                        key.push(0xff);
+                    }
+                }
+                key.extend_from_slice(&[0, 1]);
+            },
+            Literal::Null | Literal::None => key.push(0),
+        }
+    }
+    key
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, Error>{
+    bincode::serialize(value).map_err(|err| Error::Execution(err.to_string()))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, Error>{
+    bincode::deserialize(bytes).map_err(|err| Error::Corruption(err.to_string()))
+}
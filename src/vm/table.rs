@@ -1,7 +1,8 @@
-use std::collections::BTreeMap;
 use std::collections::HashMap;
 
+use crate::interpreter::stmt::Expr;
 use crate::interpreter::token::{Literal};
+use crate::storage::pager::PageNo;
 
 #[derive(Debug, Clone)]
 #[derive(serde::Deserialize)]
@@ -9,6 +10,21 @@ use crate::interpreter::token::{Literal};
 pub struct Column{ 
     pub name: String,
     pub datatype: String,
+    /// NULL is refused when set
+    pub not_null: bool,
+    /// Value the column gets when an insert leaves it out, NULL when unset
+    pub default: Option<Literal>,
+    /// Condition every row has to meet. A row is only refused when it
+    /// is false, unknown counts as met as it does in SQL
+    pub check: Option<Expr>,
+    /// No two rows may hold the same value, NULLs aside
+    pub unique: bool,
+    /// Set on the one column whose value is the key of each row
+    pub primary_key: bool,
+    /// Ids of deleted rows are never handed out again when set
+    pub autoincrement: bool,
+    /// The row of another table, or of this one, that the value refers to
+    pub references: Option<ForeignKey>,
 }
 
 impl Column {
@@ -16,6 +32,13 @@ impl Column {
         Column {
             name,
             datatype,
+            not_null: false,
+            default: None,
+            check: None,
+            unique: false,
+            primary_key: false,
+            autoincrement: false,
+            references: None,
         }
     }
 
@@ -28,6 +51,30 @@ impl Column {
     }
 }
 
+/// What happens to the rows referring to a row that is deleted
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
+pub enum OnDelete {
+    /// The delete fails
+    Restrict,
+    /// They are deleted along with it
+    Cascade,
+    /// Their reference is set to NULL
+    SetNull,
+}
+
+/// A column that holds the primary key, or a UNIQUE column, of another
+/// table. Every value that is not NULL has to match a row there
+#[derive(Debug, Clone)]
+#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
+pub struct ForeignKey {
+    pub table: String,
+    pub column: String,
+    pub on_delete: OnDelete,
+}
+
 #[derive(Debug, Clone)]
 #[derive(serde::Deserialize)]
 #[derive(serde::Serialize)]
@@ -54,8 +101,16 @@ impl Row{
 pub struct Table {
    pub name: String,
    pub columns: Vec<Column>,
-   pub rows: BTreeMap<i64, Row>, 
    pub schema: HashMap<String, String>,
+   /// First page of the B-tree holding the rows, keyed by primary key
+   pub root: PageNo,
+   /// Largest id handed out so far, the next one of an
+   /// AUTOINCREMENT primary key comes after it
+   pub sequence: i64,
+   /// UNIQUE constraints declared on their own rather than on a column,
+   /// each one a list of columns whose values together may not repeat
+   pub unique: Vec<Vec<String>>,
+   pub indexes: Vec<Index>,
 }
 
 impl Table {
@@ -63,8 +118,59 @@ impl Table {
         Table {
             name,
             columns,
-            rows: BTreeMap::new(),
             schema,
+            root: 0,
+            sequence: 0,
+            unique: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+
+    /// The column whose value is the key of each row
+    pub fn primary_key(&self) -> &Column{
+        self.columns.iter()
+            .find(|col| col.primary_key)
+            .unwrap_or(&self.columns[0])
+    }
+}
+
+/// Start of the names of the indexes created for UNIQUE constraints,
+/// which come and go with their table rather than on their own
+pub const AUTOINDEX_PREFIX: &str = "autoindex_";
+
+/// A B-tree mapping the values of some of a table's columns to the ids of
+/// the rows that hold them, kept up to date as rows change
+#[derive(Debug, Clone)]
+#[derive(serde::Deserialize)]
+#[derive(serde::Serialize)]
+pub struct Index {
+    pub name: String,
+    pub columns: Vec<String>,
+    /// Refuses a second row with the same values, rows with a NULL
+    /// in any of the columns never clash
+    pub unique: bool,
+    /// First page of the B-tree, assigned when the index is created
+    pub root: PageNo,
+    /// Created for a UNIQUE constraint rather than by CREATE INDEX, so
+    /// it lives and dies with the table
+    pub auto: bool,
+}
+
+impl Index {
+    pub fn new(name: String, columns: Vec<String>, unique: bool) -> Self{
+        Index {
+            name,
+            columns,
+            unique,
+            root: 0,
+            auto: false,
         }
     }
+
+    /// The values a row holds in the indexed columns, in index order
+    pub fn values(&self, row: &Row) -> Vec<Literal>{
+        self.columns.iter()
+            .map(|col| row.values.get(col).cloned().unwrap_or(Literal::Null))
+            .collect()
+    }
 }
@@ -0,0 +1,46 @@
+//! Fixtures shared by the tests of the planner, the compiler and the engine
+use crate::interpreter::{self, stmt::Expr};
+use crate::vm::table::{Column, Index, Table};
+use crate::{Connection, Error, Literal};
+
+/// people(id, name, age, city) with an index on age and one on (city, age)
+pub fn people() -> Table{
+    let columns: Vec<Column> = [("id", "int"), ("name", "varchar"), ("age", "int"), ("city", "varchar")].iter()
+        .map(|(name, datatype)| Column::new(name.to_string(), datatype.to_string()))
+        .collect();
+    let schema = columns.iter().map(|col| (col.name.clone(), col.datatype.clone())).collect();
+
+    let mut table = Table::new(String::from("people"), columns, schema);
+    table.columns[0].primary_key = true;
+    table.indexes.push(Index::new(String::from("byage"), vec![String::from("age")], false));
+    table.indexes.push(Index::new(String::from("bycity"), vec![String::from("city"), String::from("age")], false));
+    table
+}
+
+pub fn where_clause(condition: &str) -> Expr{
+    interpreter::interpret_expression(condition)
+        .unwrap_or_else(|err| panic!("could not parse {}: {}", condition, err))
+}
+
+/// The first column of every row a query returns, which has to be a string
+pub fn names(conn: &mut Connection, sql: &str) -> Result<Vec<String>, Error>{
+    Ok(conn.query(sql)?.into_iter()
+        .map(|row| match &row[0] {
+            Literal::String(name) => name.clone(),
+            value => panic!("expected a name, got {:?}", value),
+        })
+        .collect())
+}
+
+/// The id, parent and detail of every node EXPLAIN QUERY PLAN shows
+pub fn query_plan(conn: &mut Connection, sql: &str) -> Result<Vec<(i64, i64, String)>, Error>{
+    let rows = conn.query(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    assert_eq!(rows.columns, ["id", "parent", "detail"]);
+
+    Ok(rows.into_iter()
+        .map(|row| match &row[..] {
+            [Literal::Number(id), Literal::Number(parent), Literal::String(detail)] => (*id, *parent, detail.clone()),
+            _ => panic!("unexpected row {:?}", row),
+        })
+        .collect())
+}
@@ -1,14 +1,17 @@
-extern crate text_tables;
-
-use std::str;
 use std::collections::{HashMap, BTreeMap};
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use colored::*;
+use std::path::Path;
+use std::slice;
 
-use crate::interpreter::stmt::{Stmt};
+use crate::error::Error;
+use crate::interpreter::stmt::{Stmt, Expr};
 use crate::interpreter::token::{Literal};
-use crate::vm::table::{Table, Row, Column};
+use crate::vm::table::{Table, Row, Column, Index, ForeignKey, OnDelete, AUTOINDEX_PREFIX};
+use crate::vm::rows::Rows;
+use crate::vm::evaluator;
+use crate::vm::plan::{self, Plan};
+use crate::vm::program::Program;
+use crate::vm::storage::Storage;
+use crate::vm::{compiler, engine};
     
 #[derive(serde::Deserialize)]
 #[derive(serde::Serialize)]
@@ -17,53 +20,372 @@ pub struct Database {
     pub tables: BTreeMap<String, Table>,
 }
 
+/// What a statement hands back once it has run: the selected rows,
+/// or a success message along with how many rows were changed
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Rows(Rows),
+    Done{
+        message: String,
+        changes: usize,
+    },
+}
+
+impl Outcome {
+    fn done(message: &str, changes: usize) -> Self{
+        Outcome::Done{
+            message: String::from(message),
+            changes,
+        }
+    }
+}
+
+/// The VM owns the main database it was opened with, along with any
+/// databases attached to it under another name
 pub struct VirtualMachine {
-    command: Stmt,
+    main: Storage,
+    attached: BTreeMap<String, Storage>,
+    in_transaction: bool,
+    /// Open savepoints, oldest first. Each one matches a
+    /// savepoint level in the pager of every database
+    savepoints: Vec<String>,
+    /// Set when the transaction was started by SAVEPOINT rather than
+    /// BEGIN, releasing the outermost savepoint then commits it
+    savepoint_started: bool,
+    /// Set when a statement inside the transaction fails. Until it is
+    /// rolled back, or rolled back to a savepoint, nothing else runs
+    failed: bool,
 }
 
 impl VirtualMachine {
-    pub fn new(statement: Stmt) -> Self{
-        Self{
-           command: statement,
+    pub fn open(db_path: &Path) -> Result<Self, Error>{
+        Ok(Self{
+           main: Storage::open(db_path)?,
+           attached: BTreeMap::new(),
+           in_transaction: false,
+           savepoints: Vec::new(),
+           savepoint_started: false,
+           failed: false,
+        })
+    }
+
+    /// Runs a single statement. A statement that fails part way through never
+    /// leaves any of its changes behind. Outside of a transaction each statement
+    /// is committed as soon as it succeeds, inside one its changes are kept
+    /// until COMMIT and thrown away on ROLLBACK or when the VM is dropped.
+    /// Once a statement inside a transaction fails, every statement but
+    /// ROLLBACK and ROLLBACK TO is refused, COMMIT included, so the changes
+    /// made before the failure cannot be committed without the rest
+    pub fn run(&mut self, command: &Stmt) -> Result<Outcome, Error>{ 
+        if self.failed && !matches!(command, Stmt::Rollback | Stmt::RollbackTo{..}) {
+            return Err(Error::Execution(String::from("a statement in this transaction failed, ROLLBACK to end it")));
+        }
+
+        match command {
+            Stmt::Begin => return self.begin_transaction(),
+            Stmt::Commit => return self.commit_transaction(),
+            Stmt::Rollback => return self.rollback_transaction(),
+            Stmt::Savepoint{name} => return self.savepoint(name),
+            Stmt::Release{name} => return self.release_savepoint(name),
+            Stmt::RollbackTo{name} => return self.rollback_to_savepoint(name),
+            Stmt::Attach{path, schema_name} =>
+                return self.attach_database(path, schema_name),
+            Stmt::Detach{schema_name} =>
+                return self.detach_database(schema_name),
+            _ => {},
         }
+
+        if !self.in_transaction {
+            let result = self.execute(command)
+                .and_then(|outcome| self.commit().map(|_| outcome));
+
+            if result.is_err() {
+                self.rollback();
+            }
+            return result;
+        }
+
+        self.storages_mut().for_each(Storage::begin_savepoint);
+        let result = self.execute(command);
+
+        match result {
+            Ok(_) => self.storages_mut().for_each(Storage::release_savepoint),
+            Err(_) => {
+                self.storages_mut().for_each(Storage::rollback_savepoint);
+                self.failed = true;
+            },
+        }
+        result
+    }
+
+    /// True between BEGIN and the COMMIT or ROLLBACK that ends it
+    pub fn in_transaction(&self) -> bool{
+        self.in_transaction
     }
 
-    pub fn run(&mut self) -> Result<String, String>{ 
-        let result = match &self.command {
-            Stmt::Select{table_name, target_columns, where_conditions} => 
-                VirtualMachine::select_table(table_name, target_columns, where_conditions),
-            Stmt::Create{table_name, columns_and_data} => 
-                VirtualMachine::create_table(table_name, columns_and_data),
-            Stmt::Insert{table_name, target_columns, target_values} => 
-                VirtualMachine::insert_into_table(table_name, target_columns, target_values),
+    fn execute(&mut self, command: &Stmt) -> Result<Outcome, Error>{
+        match command {
+            Stmt::Select{..} | Stmt::Insert{..} | Stmt::Update{..} | Stmt::Delete{..} =>
+                self.run_program(command),
+            Stmt::Create{table_name, columns, unique, sequence} => 
+                self.create_table(table_name, columns, unique, *sequence),
             Stmt::Drop{table_name} => 
-                VirtualMachine::drop_table(table_name),
-            Stmt::Delete{table_name, lhs, rhs} => 
-                VirtualMachine::delete_from_table(table_name, lhs, rhs),
-            Stmt::Update{table_name, where_col, where_val, target_columns, target_values} => 
-                VirtualMachine::update_table(table_name, where_col, where_val, target_columns, target_values),
+                self.drop_table(table_name),
+            Stmt::CreateIndex{index_name, table_name, columns, unique} =>
+                self.create_index(index_name, table_name, columns, *unique),
+            Stmt::DropIndex{index_name} =>
+                self.drop_index(index_name),
+            Stmt::Explain{query_plan, statement} =>
+                self.explain(statement, *query_plan),
+            _ => Err(Error::Execution(String::from("statement cannot be run here"))),
+        }
+    }
+
+    /// The ops a statement compiles to, or with QUERY PLAN how it reads
+    /// its rows. Nothing is run either way
+    fn explain(&mut self, command: &Stmt, query_plan: bool) -> Result<Outcome, Error>{
+        if !query_plan {
+            return Ok(Outcome::Rows(self.compile(command)?.explain()));
+        }
+
+        let plan = match command {
+            Stmt::Select{table_name, target_columns, where_clause, order_by, limit} =>
+                self.plan_select(table_name, target_columns, where_clause, order_by, *limit)?.1,
+            Stmt::Update{table_name, where_clause, ..} | Stmt::Delete{table_name, where_clause} => {
+                let table = self.read_file(table_name)?;
+                evaluator::validate_expr(where_clause, &table.schema)?;
+                plan::access(&table, Some(where_clause))
+            },
+            _ => return Err(Error::Execution(String::from("only SELECT, UPDATE and DELETE statements have a plan"))),
         };
-        result
+        Ok(Outcome::Rows(plan.explain()))
+    }
+
+    /// Compiles a statement that reads or writes rows and runs it
+    /// against the database its table is in
+    fn run_program(&mut self, command: &Stmt) -> Result<Outcome, Error>{
+        let program = self.compile(command)?;
+
+        let table_name = match command {
+            Stmt::Select{table_name, ..} | Stmt::Insert{table_name, ..} |
+            Stmt::Update{table_name, ..} | Stmt::Delete{table_name, ..} => table_name,
+            _ => return Err(Error::Execution(String::from("statement does not read or write rows"))),
+        };
+        let (schema_name, _) = VirtualMachine::split_name(table_name);
+        let storage = self.storage_mut(schema_name)?;
+        let (rows, changes) = engine::run(storage, &program)?;
+
+        match command {
+            Stmt::Select{..} => Ok(Outcome::Rows(rows)),
+            Stmt::Insert{..} => Ok(Outcome::done("Command committed successfully", changes)),
+            Stmt::Update{..} => Ok(Outcome::done("Row(s) have been successfully updated", changes)),
+            _ if changes == 0 => Err(Error::Execution(String::from("Unable to remove row(s) from table"))),
+            _ => Ok(Outcome::done("Row(s) have been successfully removed from table", changes)),
+        }
+    }
+
+    /// Checks a SELECT, INSERT, UPDATE or DELETE against its table
+    /// and compiles it into a program
+    fn compile(&mut self, command: &Stmt) -> Result<Program, Error>{
+        match command {
+            Stmt::Select{table_name, target_columns, where_clause, order_by, limit} => {
+                let (table, plan) = self.plan_select(table_name, target_columns, where_clause, order_by, *limit)?;
+                Ok(compiler::select(&table, &plan))
+            },
+            Stmt::Insert{table_name, target_columns, target_values} =>
+                self.compile_insert(table_name, target_columns, target_values),
+            Stmt::Update{table_name, where_clause, target_columns, target_values} => {
+                let table = self.read_file(table_name)?;
+                evaluator::validate_expr(where_clause, &table.schema)?;
+                VirtualMachine::validate_schema(target_columns, target_values, &table.schema)?;
+
+                let plan = plan::access(&table, Some(where_clause));
+                Ok(compiler::update(&table, &plan, target_columns, target_values))
+            },
+            Stmt::Delete{table_name, where_clause} => {
+                let table = self.read_file(table_name)?;
+                evaluator::validate_expr(where_clause, &table.schema)?;
+
+                let plan = plan::access(&table, Some(where_clause));
+                Ok(compiler::delete(&table, &plan))
+            },
+            _ => Err(Error::Execution(String::from("only SELECT, INSERT, UPDATE and DELETE statements can be compiled"))),
+        }
+    }
+
+    fn begin_transaction(&mut self) -> Result<Outcome, Error>{
+        if self.in_transaction {
+            return Err(Error::Execution(String::from("cannot start a transaction within a transaction")));
+        }
+        self.in_transaction = true;
+        self.savepoint_started = false;
+        Ok(Outcome::done("Transaction started", 0))
     }
 
+    fn commit_transaction(&mut self) -> Result<Outcome, Error>{
+        if !self.in_transaction {
+            return Err(Error::Execution(String::from("cannot commit - no transaction is active")));
+        }
+        self.end_transaction();
+
+        if let Err(err) = self.commit() {
+            self.rollback();
+            return Err(err);
+        }
+        Ok(Outcome::done("Transaction committed", 0))
+    }
+
+    fn rollback_transaction(&mut self) -> Result<Outcome, Error>{
+        if !self.in_transaction {
+            return Err(Error::Execution(String::from("cannot rollback - no transaction is active")));
+        }
+        self.end_transaction();
+        self.rollback();
+        Ok(Outcome::done("Transaction rolled back", 0))
+    }
+
+    fn end_transaction(&mut self){
+        self.in_transaction = false;
+        self.savepoints.clear();
+        self.savepoint_started = false;
+        self.failed = false;
+    }
+
+    /// Starts a transaction first when none is open
+    fn savepoint(&mut self, name: &str) -> Result<Outcome, Error>{
+        if !self.in_transaction {
+            self.in_transaction = true;
+            self.savepoint_started = true;
+        }
+
+        self.savepoints.push(name.to_string());
+        self.storages_mut().for_each(Storage::begin_savepoint);
+        Ok(Outcome::done("Savepoint created", 0))
+    }
+
+    /// Keeps the changes made since the savepoint and forgets it along
+    /// with every savepoint opened after it
+    fn release_savepoint(&mut self, name: &str) -> Result<Outcome, Error>{
+        let idx = self.find_savepoint(name)?;
+
+        for _ in idx..self.savepoints.len() {
+            self.storages_mut().for_each(Storage::release_savepoint);
+        }
+        self.savepoints.truncate(idx);
+
+        if self.savepoints.is_empty() && self.savepoint_started {
+            self.commit_transaction()?;
+        }
+        Ok(Outcome::done("Savepoint released", 0))
+    }
+
+    /// Undoes every change made since the savepoint. The savepoint itself
+    /// stays open, those opened after it are removed. No savepoint can be
+    /// opened after a failure, so this also undoes the failed statement
+    fn rollback_to_savepoint(&mut self, name: &str) -> Result<Outcome, Error>{
+        let idx = self.find_savepoint(name)?;
+        self.failed = false;
+
+        for _ in idx..self.savepoints.len() {
+            self.storages_mut().for_each(Storage::rollback_savepoint);
+        }
+        self.savepoints.truncate(idx + 1);
+        self.storages_mut().for_each(Storage::begin_savepoint);
+
+        Ok(Outcome::done("Rolled back to savepoint", 0))
+    }
+
+    /// The most recent savepoint with this name wins
+    fn find_savepoint(&self, name: &str) -> Result<usize, Error>{
+        self.savepoints.iter()
+            .rposition(|savepoint| savepoint == name)
+            .ok_or_else(|| Error::Execution(format!("no such savepoint: {}", name)))
+    }
+
+    /// Names of every table, those in attached databases are
+    /// qualified with the name the database was attached as
+    pub fn table_names(&mut self) -> Result<Vec<String>, Error>{
+        let mut names: Vec<String> = self.main.tables()?.tables.into_keys().collect();
+
+        for (schema_name, storage) in &mut self.attached{
+            for table in storage.tables()?.tables.into_keys(){
+                names.push(format!("{}.{}", schema_name, table));
+            }
+        }
+        Ok(names)
+    }
+
+    /// Every table definition in the main database
+    pub fn tables(&mut self) -> Result<Database, Error>{
+        self.main.tables()
+    }
+
+    /// The definition of a table, or None when there is no such table
+    pub fn table(&mut self, tablename: &str) -> Result<Option<Table>, Error>{
+        let (schema_name, table_name) = VirtualMachine::split_name(tablename);
+        self.storage_mut(schema_name)?.table(table_name)
+    }
 
-    fn select_table( 
-                    table_name: &str, 
-                    target_columns: &Vec<String>,
-                    where_conditions: &Option<(Vec<String>, Vec<Literal>)>
-                    ) -> Result<String, String>{
+    /// Visits every row of a table in id order without collecting them, so
+    /// tables of any size can be copied out. The values of each row are in
+    /// the order of the table's columns
+    pub fn scan_table(&mut self,
+                      tablename: &str,
+                      mut visit: impl FnMut(&[Literal]) -> Result<(), Error>) -> Result<(), Error>{
+        let table = self.read_file(tablename)?;
+        let (schema_name, _) = VirtualMachine::split_name(tablename);
+
+        self.storage_mut(schema_name)?.scan(&table, |_, row| {
+            let values: Vec<Literal> = table.columns.iter()
+                .map(|column| row.values.get(&column.name).cloned().unwrap_or(Literal::Null))
+                .collect();
+            visit(&values)
+        })
+    }
+
+    fn attach_database(&mut self, path: &str, schema_name: &str) -> Result<Outcome, Error>{
+        if schema_name == "main" || self.attached.contains_key(schema_name){
+            return Err(Error::Execution(format!("database {} is already in use", schema_name)));
+        }
+
+        let mut storage = Storage::open(Path::new(path))?;
+        // make sure the file really is a database before handing it out
+        storage.tables()?;
+        // and give it the savepoints the other databases already have
+        for _ in &self.savepoints {
+            storage.begin_savepoint();
+        }
+        self.attached.insert(schema_name.to_string(), storage);
+
+        Ok(Outcome::done("Database attached successfully", 0))
+    }
+
+    fn detach_database(&mut self, schema_name: &str) -> Result<Outcome, Error>{
+        if matches!(self.attached.get(schema_name), Some(storage) if storage.has_changes()){
+            return Err(Error::Execution(format!("database {} has uncommitted changes", schema_name)));
+        }
+
+        match self.attached.remove(schema_name){
+            Some(_) => Ok(Outcome::done("Database detached successfully", 0)),
+            None => Err(Error::Execution(format!("no such database: {}", schema_name))),
+        }
+    }
+
+
+    /// Checks the columns, where clause and sort keys of a SELECT against
+    /// its table and plans how to read it
+    fn plan_select(&mut self,
+                   table_name: &str,
+                   target_columns: &[String],
+                   where_clause: &Option<Expr>,
+                   order_by: &[(String, bool)],
+                   limit: Option<usize>) -> Result<(Table, Plan), Error>{
    
-        let target_table: Table =  VirtualMachine::read_file(&table_name)?;
+        let target_table: Table = self.read_file(table_name)?;
         
-        let table_name = &target_table.name;
+        let is_all = matches!(target_columns.first(), Some(val) if val == "*");
 
-        let is_all = match target_columns.get(0){
-           Some(val) if val == "*" => true,
-           _ => false,
-        };
-
-        let mut table_data: Vec<Vec<String>> = Vec::new();
-        let cols = if is_all{
+        let cols: Vec<String> = if is_all{
             target_table.columns.iter()
                                     .map(|s| s.name.clone())
                                     .collect()
@@ -71,350 +393,404 @@ impl VirtualMachine {
            target_columns.to_vec()
         };
 
-        let (where_col, where_vals): (Vec<String>, Vec<Literal>) = match where_conditions {
-            Some((wc, wv)) => (wc.to_vec(), wv.to_vec()),
-            None => (Vec::new(), Vec::new()),
-        };
+        if let Some(col) = cols.iter().find(|col| !target_table.schema.contains_key(*col)){
+            return Err(Error::UnknownColumn(col.to_string()));
+        }
 
-        VirtualMachine::validate_schema(&where_col, 
-                                        &where_vals, 
-                                        &target_table.schema)?;
-
-        table_data.push(cols.clone());
-
-        if where_col.len() > 0 && where_vals.len() > 0{
-
-            let ids =  VirtualMachine::collect_target_ids(&target_table.rows, 
-                                                    &where_col,
-                                                    &where_vals)?; 
-            for id in &ids{
-                let mut row_data: Vec<String> = Vec::new();
-
-                for column in &cols{
-                   if column == "id" {
-                       row_data.push(id.to_string());
-                   }
-                   let row = match target_table.rows.get(id){
-                    Some(r) => r,
-                    _ => return Err(String::from("invalid target row")),
-                   }; 
-
-                   let column_data = row.values.get(column);
-                   match column_data {
-                    Some(data) => {
-                        let data_val = match data {
-                            Literal::Number(val) => val.to_string(),
-                            Literal::String(val) => String::from(val),
-                            Literal::Boolean(val) => val.to_string(),
-                            Literal::Null => String::from("NULL"),
-                            _ => String::from(""), // throws err
-                        };
-                        row_data.push(data_val);
-                    },
-                    None => {}, 
-                   }
-                }
-                table_data.push(row_data); 
-               }          
-        } else {
-        //just push through the rows no filter
-            for (id, row) in target_table.rows.into_iter(){
-               let mut row_data: Vec<String> = Vec::new();
-                
-               for column in &cols{
-
-                   if column == "id" {
-                       row_data.push(id.to_string());
-                   }
-
-                   let column_data = row.values.get(column);
-                   match column_data {
-                    Some(data) => {
-                        let data_val = match data {
-                            Literal::Number(val) => val.to_string(),
-                            Literal::String(val) => String::from(val),
-                            Literal::Boolean(val) => val.to_string(),
-                            Literal::Null => String::from("NULL"),
-                            _ => String::from(""), 
-                        };
-                        row_data.push(data_val);
-                    },
-                    None => {}, 
-                   }
-               }
-               table_data.push(row_data);
-            }
+        if let Some(expr) = where_clause {
+            evaluator::validate_expr(expr, &target_table.schema)?;
+        }
+
+        // rows can be sorted by any of their columns, selected or not
+        if let Some((col, _)) = order_by.iter().find(|(col, _)| !target_table.schema.contains_key(col)){
+            return Err(Error::UnknownColumn(col.to_string()));
         }
-        // => pipe the table into a formatting printer function
-        let mut out = Vec::new();
-        text_tables::render(&mut out, table_data).unwrap();
-        println!("\n--{}--", table_name.to_uppercase());
-        println!("{}", str::from_utf8(&out).unwrap());
-        Ok(String::from(""))
+
+        let plan = plan::select(&target_table, &cols, where_clause.as_ref(), order_by, limit);
+        Ok((target_table, plan))
     }
 
-    fn create_table(name: &str,
-                    data: &Vec<(String, String)>) -> Result<String, String>{
+    fn create_table(&mut self,
+                    name: &str,
+                    data: &[Column],
+                    unique: &[Vec<String>],
+                    sequence: Option<i64>) -> Result<Outcome, Error>{
 
-        let mut columns: Vec<Column> = Vec::new();
+        let mut columns: Vec<Column> = data.to_vec();
         let mut schema: HashMap<String,String> = HashMap::new();
-        let mut idx = 0;
-
-        for (col_name, datatype) in data{
-            if idx == 0 && col_name != "id"{
-                //first column should be ID, otherwise we make it ID
-                //other ID fields should be explicitly the id for a FK relationship
-                columns.push(Column::new(String::from("id"), String::from("int")));
-                schema.insert(String::from("id"), String::from("int"));
+
+        for column in &columns{
+            if schema.insert(column.name.clone(), column.datatype.clone()).is_some(){
+                return Err(Error::Execution(String::from("cannot have duplicate column names")));
             }
+        }
 
-            if schema.contains_key(col_name) && col_name != "id"{
-                return Err(String::from("cannot have duplicate column names"));
-            } else if schema.contains_key(col_name) && col_name == "id"{
-                continue;
-            } 
-            columns.push(Column::new(String::from(col_name), String::from(datatype)));
-            schema.insert(String::from(col_name), String::from(datatype));
-            idx +=1;
+        // without a declared primary key, a column named id is the key,
+        // and when there is none either one is added in front
+        match columns.iter().filter(|col| col.primary_key).count() {
+            0 => match columns.iter_mut().find(|col| col.name == "id") {
+                Some(column) => column.primary_key = true,
+                None => {
+                    let mut id = Column::new(String::from("id"), String::from("int"));
+                    id.primary_key = true;
+                    columns.insert(0, id);
+                    schema.insert(String::from("id"), String::from("int"));
+                },
+            },
+            1 => {},
+            _ => return Err(Error::Execution(format!("table {} has more than one primary key", name))),
         }
 
-        let table = Table::new(name.to_string(), columns, schema);
-        VirtualMachine::write_file(table)?;
+        if let Some(column) = columns.iter().find(|col| col.primary_key && col.datatype != "int"){
+            return Err(Error::Execution(format!("primary key {} must be an int column", column.name)));
+        }
+        if sequence.is_some() && !columns.iter().any(|col| col.primary_key && col.autoincrement){
+            return Err(Error::Execution(format!("table {} has no AUTOINCREMENT primary key", name)));
+        }
 
-        //if written, we print to user success, then return Ok
-        Ok(String::from("Table created successfully"))
-    }
+        // constraints are checked once every column is known,
+        // as a check may refer to any column of the table
+        for column in &columns{
+            if let Some(default) = &column.default {
+                VirtualMachine::validate_schema(slice::from_ref(&column.name), slice::from_ref(default), &schema)?;
+            }
+            if let Some(check) = &column.check {
+                evaluator::validate_expr(check, &schema)?;
+            }
+        }
 
+        let (schema_name, table_name) = VirtualMachine::split_name(name);
+        let storage = self.storage_mut(schema_name)?;
 
-    fn insert_into_table(name: &str, 
-                         columns: &Vec<String>, 
-                         values: &Vec<Literal>) -> Result<String, String>{
+        if storage.table(table_name)?.is_some(){
+            return Err(Error::Execution(format!("table {} already exists", name)));
+        }
 
-        let mut target_table: Table = VirtualMachine::read_file(&name)?;
+        let mut table = Table::new(table_name.to_string(), columns, schema);
+        table.unique = unique.to_vec();
+        table.sequence = sequence.unwrap_or(0);
+
+        // every UNIQUE constraint is kept by an index of its own
+        let constrained: Vec<Vec<String>> = table.columns.iter()
+            .filter(|col| col.unique)
+            .map(|col| vec![col.name.clone()])
+            .chain(table.unique.iter().cloned())
+            .collect();
+
+        for (idx, columns) in constrained.into_iter().enumerate(){
+            for (pos, column) in columns.iter().enumerate(){
+                if !table.schema.contains_key(column) {
+                    return Err(Error::UnknownColumn(column.to_string()));
+                }
+                if columns[..pos].contains(column) {
+                    return Err(Error::Execution(format!("column {} appears twice in a UNIQUE constraint", column)));
+                }
+            }
 
-        let id: i64 = if columns.len() <= 0 || (columns.len() > 0 && !columns.contains(&"id".to_string())){
+            let mut index = Index::new(format!("{}{}_{}", AUTOINDEX_PREFIX, table_name, idx + 1), columns, true);
+            index.auto = true;
+            storage.create_index(&mut index)?;
+            table.indexes.push(index);
+        }
 
-            let has_potential_id = match values.get(0){
-                Some(val) => val,
-                _ => &Literal::None,
+        // a reference may name a table that does not exist yet, it
+        // is then checked when rows refer to it instead
+        for column in &table.columns{
+            let Some(fk) = &column.references else { continue };
+            let parent = if fk.table == table.name {
+                Some(table.clone())
+            } else {
+                storage.table(&fk.table)?
             };
 
-            match has_potential_id {
-                Literal::Number(val) => *val,
-                _ => (target_table.rows.len() + 1) as i64, 
+            if let Some(parent) = parent {
+                match parent.schema.get(&fk.column) {
+                    None => return Err(Error::UnknownColumn(format!("{}.{}", fk.table, fk.column))),
+                    Some(datatype) if *datatype != column.datatype =>
+                        return Err(Error::TypeMismatch(format!("{}.{} is {} but refers to {}.{} which is {}",
+                                    table.name, column.name, column.datatype, fk.table, fk.column, datatype))),
+                    _ => {},
+                }
+                if !VirtualMachine::is_parent_key(&parent, &fk.column) {
+                    return Err(Error::Execution(format!("{}.{} must be the primary key or UNIQUE to be referred to",
+                                                        fk.table, fk.column)));
+                }
             }
+        }
 
-        } else {
-            //need to both extract the ID from the values list based on 
-            //the index of the column name id, AND filter them out of the 
-            //column list
-
-            let id_location = match columns.iter().position(|name| name == "id"){
-                Some(loc) => loc,
-                None => 0,
-            };
-            
-            let id_exists = match values.get(id_location){
-                Some(val) => val,
-                None => &Literal::Null,
-            };
-            
-            let id = match id_exists {
-                Literal::Number(val) => val,
-                _ => &(0 as i64),
-            };
-
-            *id 
-        };
+        storage.create_table(table)?;
 
-        let row_vals: Vec<Literal> = values.iter()
-                                     .filter(|val| **val != Literal::Number(id))
-                                     .map(|lit| lit.clone())
-                                     .collect();
+        //if written, we report success back to the caller
+        Ok(Outcome::done("Table created successfully", 0))
+    }
 
-        let col_names: Vec<String> = target_table.columns.iter()
-                                    .filter(|id| id.name != "id")
-                                    .map(|s| s.name.clone())
-                                    .collect();
 
+    fn compile_insert(&mut self,
+                      name: &str, 
+                      columns: &[String], 
+                      values: &[Literal]) -> Result<Program, Error>{
 
-        if columns.len() <= 0 && col_names.len() != row_vals.len(){ 
-                    return Err(String::from("Must include values for all columns when target columns are omitted"));
-       }
+        let target_table: Table = self.read_file(name)?;
 
-        if columns.len() > 0 {
-            VirtualMachine::validate_schema(&columns, &values, &target_table.schema)?;
-        } else {
-            VirtualMachine::validate_schema(&col_names, &row_vals, &target_table.schema)?;
-        }  
-
-        let row = if columns.len() <= 0 && col_names.len() == row_vals.len(){
-            Row::new(col_names, row_vals)
-        } else if columns.len() <= 0 && col_names.len() != row_vals.len(){ // second time just in
-                                                                           // case 
-            return Err(String::from("Must include values for all columns when target columns are omitted"));
+        // without target columns there is a value for every column, or
+        // for every column but the primary key when it is to be generated
+        let col_names: Vec<String> = if !columns.is_empty() {
+            columns.to_vec()
+        } else if values.len() == target_table.columns.len() {
+            target_table.columns.iter().map(|col| col.name.clone()).collect()
+        } else if values.len() + 1 == target_table.columns.len() {
+            target_table.columns.iter()
+                .filter(|col| !col.primary_key)
+                .map(|col| col.name.clone())
+                .collect()
         } else {
-            let mut filled_rows: Vec<Literal> = Vec::new();
-
-            for col in &target_table.columns{
-               
-                if &col.name == "id"{
-                    continue;
-               }
-
-               if columns.contains(&col.name){
-                    let idx = match columns.iter().position(|x| x == &col.name){
-                        Some(i) => i,
-                        None => return Err(String::from("Invalid column sequence")),
-                    }; 
-                    filled_rows.push(values[idx].clone());
-               } else {
-                   //adds default value in place of empty space
-                    let filler_val = match &*col.datatype {
-                        "varchar" => Literal::String(String::from("")),
-                        "int" => Literal::Number(0),
-                        "bool" => Literal::Boolean(false),
-                        _ => Literal::String(String::from("")),
-                    }; 
-                    filled_rows.push(filler_val);
-               } 
-            }
-            Row::new(col_names, filled_rows)
+            return Err(Error::Execution(String::from("Must include values for all columns when target columns are omitted")));
         };
-        target_table.rows.insert(id, row);
 
-        VirtualMachine::write_file(target_table)?;
+        if col_names.len() != values.len() {
+            return Err(Error::Execution(format!("{} values given for {} columns", values.len(), col_names.len())));
+        }
+        VirtualMachine::validate_schema(&col_names, values, &target_table.schema)?;
 
-        Ok(String::from("Command committed successfully"))
+        Ok(compiler::insert(&target_table, &col_names, values))
     }
 
-    fn drop_table(name: &String) -> Result<String, String>{
-     
-        let get_file = OpenOptions::new()
-                                    .read(true)
-                                    .write(true)
-                                    .create(true)
-                                    .open("data/database.rdb");
-
-        let mut file = get_file.map_err(|err| err.to_string())?;
-
-        let mut buff = Vec::new();
-        file.read_to_end(&mut buff).map_err(|err| err.to_string())?;
-        
-        let mut memory_db: Database = match bincode::deserialize(&buff){
-            Ok(exists) => exists,
-            Err(_) => {
-                println!("{}", "No database found.. creating new DB instance".yellow());
-                Database{
-                    tables : BTreeMap::new(),
+    /// Adds a row holding values for the named columns, the others get their
+    /// default. The primary key is handed out when it is left out, and the
+    /// row is refused when it breaks a constraint or refers to nothing
+    pub(super) fn insert_row(storage: &mut Storage,
+                             table: &mut Table,
+                             col_names: &[String],
+                             values: &[Literal]) -> Result<(), Error>{
+
+        let primary_key = table.primary_key().clone();
+
+        let filled_rows: Vec<Literal> = table.columns.iter()
+            .map(|col| match col_names.iter().position(|name| *name == col.name) {
+                Some(idx) => values[idx].clone(),
+                //columns left out of the statement get their default
+                None => col.default.clone().unwrap_or(Literal::Null),
+            })
+            .collect();
+        let mut row = Row::new(table.columns.iter().map(|col| col.name.clone()).collect(), filled_rows);
+
+        let id = match row.values.get(&primary_key.name) {
+            Some(Literal::Number(id)) => *id,
+            _ => {
+                // a missing key comes after the largest one in the table, with
+                // AUTOINCREMENT also after any that were handed out and deleted
+                let mut last = storage.last_id(table)?.unwrap_or(0);
+                if primary_key.autoincrement {
+                    last = last.max(table.sequence);
                 }
+                let id = last.checked_add(1)
+                    .ok_or_else(|| Error::Execution(format!("table {} has run out of ids", table.name)))?;
+                row.values.insert(primary_key.name.clone(), Literal::Number(id));
+                id
             },
         };
 
-        let res = match memory_db.tables.remove(name){
-            Some(_) => Ok(String::from("Table dropped successfully")),
-            None => Err(String::from("Unable to remove table")),
-        };
+        if storage.row(table, id)?.is_some() {
+            return Err(VirtualMachine::duplicate_key(table, id));
+        }
+
+        VirtualMachine::check_constraints(table, &row)?;
+        VirtualMachine::index_row(storage, table, id, &row)?;
+        storage.put_row(table, id, &row)?;
+        // only now, as a row may refer to itself
+        VirtualMachine::check_references(storage, table, &row)?;
 
-       
-        if !res.is_err() {
-            let encode: Vec<u8> = bincode::serialize(&memory_db).unwrap();
-            let mut file =  File::create("data/database.rdb")
-                .map_err(|err| err.to_string())?;
-            file.write_all(&encode).map_err(|err| err.to_string())?; 
+        if primary_key.autoincrement && id > table.sequence {
+            table.sequence = id;
+            storage.save_table(table)?;
         }
-       res 
+        Ok(())
     }
 
+    /// Builds the index from the rows already in the table. A UNIQUE
+    /// index cannot be created over rows that repeat its values
+    fn create_index(&mut self,
+                    index_name: &str,
+                    name: &str,
+                    columns: &[String],
+                    unique: bool) -> Result<Outcome, Error>{
+
+        let mut table = self.read_file(name)?;
+        for (pos, column) in columns.iter().enumerate(){
+            if !table.schema.contains_key(column) {
+                return Err(Error::UnknownColumn(column.to_string()));
+            }
+            if columns[..pos].contains(column) {
+                return Err(Error::Execution(format!("column {} appears twice in index {}", column, index_name)));
+            }
+        }
 
-    fn delete_from_table(name: &String,
-                         columns: &Vec<String>,
-                         values: &Vec<Literal>) -> Result<String, String>{
- 
-        let mut target_table: Table = VirtualMachine::read_file(&name)?;
+        let (schema_name, _) = VirtualMachine::split_name(name);
+        let storage = self.storage_mut(schema_name)?;
 
-        VirtualMachine::validate_schema(columns, values, &target_table.schema)?;
+        if storage.table(index_name)?.is_some() {
+            return Err(Error::Execution(format!("there is already a table named {}", index_name)));
+        }
+        if VirtualMachine::find_index(storage, index_name)?.is_some() {
+            return Err(Error::Execution(format!("index {} already exists", index_name)));
+        }
 
-        let ids: Vec<i64> = VirtualMachine::collect_target_ids(&target_table.rows, 
-                                                          columns, 
-                                                          values)?;
- 
-        let mut success: bool = false;
-        for id in ids{
-            match target_table.rows.remove(&id){
-                Some(_) => success = true,
-                None => {},
-            }
-       }
+        let mut index = Index::new(index_name.to_string(), columns.to_vec(), unique);
+        storage.create_index(&mut index)?;
 
-        match VirtualMachine::write_file(target_table){
-            Ok(_) => {
-                if success != true{
-                    return Err(String::from("Unable to remove row(s) from table"));
-                }
-            },
-            Err(err) => return Err(err),
+        let mut entries: Vec<(i64, Row)> = Vec::new();
+        storage.scan(&table, |id, row| {
+            entries.push((id, row));
+            Ok(())
+        })?;
+        for (id, row) in &entries{
+            VirtualMachine::index_entry(storage, &table, &index, *id, row)?;
         }
-       Ok(String::from("Row(s) have been successfully removed from table")) 
+
+        table.indexes.push(index);
+        storage.save_table(&table)?;
+        Ok(Outcome::done("Index created successfully", 0))
     }
 
+    fn drop_index(&mut self, name: &str) -> Result<Outcome, Error>{
+        let (schema_name, index_name) = VirtualMachine::split_name(name);
+        let storage = self.storage_mut(schema_name)?;
 
-    fn update_table( name: &String,
-                    where_cols: &Vec<String>,
-                    where_vals: &Vec<Literal>, 
-                    target_cols: &Vec<String>,
-                    target_vals: &Vec<Literal>) -> Result<String, String>{
-      
+        let (mut table, pos) = match VirtualMachine::find_index(storage, index_name)? {
+            Some(found) => found,
+            None => return Err(Error::Execution(format!("no such index: {}", name))),
+        };
+        if table.indexes[pos].auto {
+            return Err(Error::Execution(format!("index {} belongs to a UNIQUE constraint of {} and cannot be dropped",
+                                                index_name, table.name)));
+        }
 
-        let mut target_table: Table = VirtualMachine::read_file(name)?;
+        let index = table.indexes.remove(pos);
+        storage.drop_index(&index)?;
+        storage.save_table(&table)?;
+        Ok(Outcome::done("Index dropped successfully", 0))
+    }
 
-        VirtualMachine::validate_schema(where_cols, where_vals, &target_table.schema)?;
+    /// The table an index belongs to, along with its position in the table's indexes
+    fn find_index(storage: &mut Storage, index_name: &str) -> Result<Option<(Table, usize)>, Error>{
+        for table in storage.tables()?.tables.into_values(){
+            if let Some(pos) = table.indexes.iter().position(|index| index.name == index_name) {
+                return Ok(Some((table, pos)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn drop_table(&mut self, name: &str) -> Result<Outcome, Error>{
+        let (schema_name, table_name) = VirtualMachine::split_name(name);
+        let storage = self.storage_mut(schema_name)?;
+
+        let table = match storage.table(table_name)?{
+            Some(table) => table,
+            None => return Err(Error::UnknownTable(name.to_string())),
+        };
+
+        // rows of other tables that refer to this one get their
+        // ON DELETE action, as if every row was deleted first
+        let children: Vec<(Table, Column)> = VirtualMachine::referencing(storage, &table.name)?
+            .into_iter()
+            .filter(|(child, _)| child.name != table.name)
+            .collect();
+
+        if !children.is_empty() {
+            let mut rows: Vec<Row> = Vec::new();
+            storage.scan(&table, |_, row| {
+                rows.push(row);
+                Ok(())
+            })?;
+
+            for row in &rows{
+                VirtualMachine::apply_on_delete(storage, row, &children)?;
+            }
+        }
+
+        storage.drop_table(&table)?;
+        Ok(Outcome::done("Table dropped successfully", 0))
+    }
 
-        let ids = VirtualMachine::collect_target_ids(&target_table.rows, where_cols, where_vals)?;
 
-        // now that we have the IDs, we can get the rows, then replace the 
-        // target columns with the target values
+    /// Sets the named columns of a row. A new primary key moves the row,
+    /// and a key the rows of `children` still refer to cannot be changed
+    pub(super) fn update_row(storage: &mut Storage,
+                             table: &mut Table,
+                             children: &[(Table, Column)],
+                             id: i64,
+                             target_cols: &[String],
+                             target_vals: &[Literal]) -> Result<(), Error>{
+
+        let primary_key = table.primary_key().clone();
+
+        let old_row = match storage.row(table, id)?{
+            Some(inner_row) => inner_row,
+            None => return Err(Error::Corruption(format!("row {} of table '{}' is missing", id, table.name))),
+        };
+        let mut row_replacement = old_row.clone();
         
-        for id in &ids{
-            let row = match target_table.rows.get(id){
-                Some(inner_row) => inner_row,
-                None => return Err(String::from("Invalid row in table")),
-            };
+        for (col, val) in target_cols.iter().zip(target_vals){
+            row_replacement.values.insert(col.to_string(), val.clone());       
+        }
 
-            VirtualMachine::validate_schema(&target_cols, &target_vals, &target_table.schema)?;
+        VirtualMachine::check_constraints(table, &row_replacement)?;
 
-            let mut row_replacement = row.clone();
-            
-            for (col, val) in target_cols.into_iter().zip(target_vals){
-                row_replacement.values.insert(col.to_string(), val.clone());       
+        // changing the primary key moves the row to its new key
+        let new_id = match row_replacement.values.get(&primary_key.name) {
+            Some(Literal::Number(new_id)) => *new_id,
+            _ => return Err(Error::ConstraintViolation(
+                format!("NOT NULL constraint failed: {}.{}", table.name, primary_key.name))),
+        };
+        if new_id != id {
+            if storage.row(table, new_id)?.is_some() {
+                return Err(VirtualMachine::duplicate_key(table, new_id));
             }
- 
-            target_table.rows.insert(*id, row_replacement);
+            storage.delete_row(table, id)?;
         }
 
-        VirtualMachine::write_file(target_table)?;
-        Ok(String::from("Row(s) have been successfully updated"))
+        VirtualMachine::unindex_row(storage, table, id, &old_row)?;
+        VirtualMachine::index_row(storage, table, new_id, &row_replacement)?;
+        storage.put_row(table, new_id, &row_replacement)?;
+        VirtualMachine::check_references(storage, table, &row_replacement)?;
+
+        // a key that other rows still refer to cannot be changed
+        for (child, column) in children{
+            let Some(fk) = &column.references else { continue };
+            let old_key = old_row.values.get(&fk.column).unwrap_or(&Literal::Null);
+            if *old_key == Literal::Null || Some(old_key) == row_replacement.values.get(&fk.column) {
+                continue;
+            }
+            if !VirtualMachine::find_rows(storage, child, &column.name, old_key)?.is_empty() {
+                return Err(VirtualMachine::still_referenced(child, column, fk, old_key));
+            }
+        }
+
+        if primary_key.autoincrement && new_id > table.sequence {
+            table.sequence = new_id;
+            storage.save_table(table)?;
+        }
+        Ok(())
     }
     
-    fn validate_schema(col_names: &Vec<String>,
-                       values: &Vec<Literal>,
-                       schema: &HashMap<String, String>) -> Result<(), String> {
+    fn validate_schema(col_names: &[String],
+                       values: &[Literal],
+                       schema: &HashMap<String, String>) -> Result<(), Error> {
 
         for (name, val) in col_names.iter().zip(values){
-            let col_type = match schema.get(&String::from(name)){
+            let col_type = match schema.get(name){
                 Some(inner_type) => inner_type, 
-                None => {
-                    continue;
-                },
+                None => return Err(Error::UnknownColumn(name.to_string())),
             };
             
             match val {
                 Literal::String(_) if col_type != "varchar" =>
-                     return Err("Invalid column-type combination for varchar".to_string()),
+                     return Err(Error::TypeMismatch("Invalid column-type combination for varchar".to_string())),
                 Literal::Number(_) if col_type != "int" => 
-                     return Err("Invalid column-type combination for number".to_string()),
+                     return Err(Error::TypeMismatch("Invalid column-type combination for number".to_string())),
                 Literal::Boolean(_) if col_type != "bool" => 
-                     return Err("Invalid column-type combination for bool".to_string()),
+                     return Err(Error::TypeMismatch("Invalid column-type combination for bool".to_string())),
                 _ => {},
             }
         }
@@ -423,129 +799,584 @@ impl VirtualMachine {
     }
 
 
-    fn collect_target_ids(rows: &BTreeMap<i64, Row>, 
-                          columns: &Vec<String>, 
-                          values: &Vec<Literal>) -> Result<Vec<i64>, String>{
-        let mut ids: Vec<i64> = Vec::new();
-        for (id, row) in rows{
+    /// Refuses a row that breaks a NOT NULL or CHECK constraint of its table
+    fn check_constraints(table: &Table, row: &Row) -> Result<(), Error>{
+        for column in &table.columns{
 
-            for (col, val) in columns.into_iter().zip(values) {
-                if col == "id" {
-                    let inner_id = match val{
-                        Literal::Number(inner_id) => inner_id,
-                        _ => return Err(String::from("Invalid input for ID value")),
-                    };
-
-                    if *inner_id == *id as i64{
-                        ids.push(*id);
-                    } 
+            let value = row.values.get(&column.name).unwrap_or(&Literal::Null);
+            if column.not_null && *value == Literal::Null {
+                return Err(Error::ConstraintViolation(
+                    format!("NOT NULL constraint failed: {}.{}", table.name, column.name)));
+            }
 
-                    continue;
+            if let Some(check) = &column.check {
+                if evaluator::evaluate(check, row)? == Literal::Boolean(false) {
+                    return Err(Error::ConstraintViolation(
+                        format!("CHECK constraint failed: {}.{} ({})", table.name, column.name, check)));
                 }
+            }
+        }
+        Ok(())
+    }
 
-                match row.values.get(col) {
-                    Some(Literal::String(row_val))  => {
-
-                        let inner_val = match val {
-                            Literal::String(inner) => inner,
-                            _ => continue, 
-                        };
-
-                        if *row_val == *inner_val{
-                            ids.push(*id);
-                        } 
-                    },
-                    Some(Literal::Number(row_val)) => {
-                        
-                        let inner_val = match val {
-                            Literal::Number(inner) => inner,
-                            _ => continue, 
-                        };
-                        if *row_val == *inner_val{
-                            ids.push(*id);
-                        }
-                    }, 
-                    Some(Literal::Boolean(row_val)) => {
-                        //find a way to convert
-                        let inner_val = match val {
-                            Literal::Boolean(inner) => inner,
-                            _ => continue, 
-                        };
-                        if *row_val == *inner_val{
-                            ids.push(*id);
-                        } 
-                    },
-                    Some(Literal::Null) => {
-                       if *val == Literal::Null{
-                            ids.push(*id);
-                       } 
-                    },
-                    Some(Literal::None) => continue,
-                    _ => continue,
-                }
-            }            
+    /// Adds a row to every index of its table, refusing it when a UNIQUE
+    /// index already holds another row with the same values. The lookup
+    /// only reads the index, the rows themselves are never scanned
+    fn index_row(storage: &mut Storage, table: &Table, id: i64, row: &Row) -> Result<(), Error>{
+        for index in &table.indexes{
+            VirtualMachine::index_entry(storage, table, index, id, row)?;
         }
-        Ok(ids)
+        Ok(())
     }
-    
-    fn read_file(tablename: &str) -> Result<Table, String> { 
-       
-        let get_file = OpenOptions::new()
-                                    .read(true)
-                                    .write(true)
-                                    .create(true)
-                                    .open("data/database.rdb");
-
-        let mut file = get_file.map_err(|err| err.to_string())?;
-        
-        let mut buff = Vec::new();
-        file.read_to_end(&mut buff).map_err(|err| err.to_string())?;        
-        let memory_db: Database = match bincode::deserialize(&buff){
-            Ok(exists) => exists,
-            Err(_) => {
-                println!("{}", "No database found.. creating new DB instance".yellow());
-                Database{
-                    tables : BTreeMap::new(),
-                }
-            },
+
+    fn index_entry(storage: &mut Storage, table: &Table, index: &Index, id: i64, row: &Row) -> Result<(), Error>{
+        let values = index.values(row);
+
+        // NULL is never equal to anything, so it cannot repeat either
+        if index.unique && !values.contains(&Literal::Null) &&
+            !storage.index_lookup(index, &values)?.is_empty() {
+            let columns: Vec<String> = index.columns.iter()
+                .map(|col| format!("{}.{}", table.name, col))
+                .collect();
+            return Err(Error::ConstraintViolation(
+                format!("UNIQUE constraint failed: {}", columns.join(", "))));
+        }
+        storage.index_add(index, &values, id)
+    }
+
+    fn unindex_row(storage: &mut Storage, table: &Table, id: i64, row: &Row) -> Result<(), Error>{
+        for index in &table.indexes{
+            storage.index_remove(index, &index.values(row), id)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes rows by id, see `delete_row`. Returns the
+    /// number of rows deleted, those removed by a cascade aside
+    fn delete_rows(storage: &mut Storage, table: &Table, ids: &[i64]) -> Result<usize, Error>{
+        let children = VirtualMachine::referencing(storage, &table.name)?;
+
+        let mut removed: usize = 0;
+        for id in ids{
+            if VirtualMachine::delete_row(storage, table, *id, &children)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Deletes a row along with its index entries, and applies the ON DELETE
+    /// action of every foreign key of `children` referring to it. Returns
+    /// false when there was no such row
+    pub(super) fn delete_row(storage: &mut Storage,
+                             table: &Table,
+                             id: i64,
+                             children: &[(Table, Column)]) -> Result<bool, Error>{
+        // a cascade may already have removed it
+        let row = match storage.row(table, id)? {
+            Some(row) => row,
+            None => return Ok(false),
         };
 
-        match memory_db.tables.get(tablename){
-            Some(table) => Ok(table.clone()),
-            None => return Err(String::from("Target table not found")),
+        VirtualMachine::unindex_row(storage, table, id, &row)?;
+        let removed = storage.delete_row(table, id)?;
+        VirtualMachine::apply_on_delete(storage, &row, children)?;
+        Ok(removed)
+    }
+
+    /// Deletes, sets to NULL or refuses to orphan the rows of the
+    /// children that refer to a row which is being deleted
+    fn apply_on_delete(storage: &mut Storage,
+                       row: &Row,
+                       children: &[(Table, Column)]) -> Result<(), Error>{
+
+        for (child, column) in children{
+            let Some(fk) = &column.references else { continue };
+            let key = row.values.get(&fk.column).unwrap_or(&Literal::Null);
+            if *key == Literal::Null {
+                continue;
+            }
+
+            let ids = VirtualMachine::find_rows(storage, child, &column.name, key)?;
+            if ids.is_empty() {
+                continue;
+            }
+
+            match fk.on_delete {
+                OnDelete::Restrict => return Err(VirtualMachine::still_referenced(child, column, fk, key)),
+                OnDelete::Cascade => {
+                    VirtualMachine::delete_rows(storage, child, &ids)?;
+                },
+                // the child row is updated like any other, so its own
+                // constraints and the rows referring to it are checked
+                OnDelete::SetNull => {
+                    let grandchildren = VirtualMachine::referencing(storage, &child.name)?;
+                    for id in ids {
+                        VirtualMachine::update_row(storage, &mut child.clone(), &grandchildren, id,
+                                                   slice::from_ref(&column.name), &[Literal::Null])?;
+                    }
+                },
+            }
         }
+        Ok(())
     }
 
-    //simply writes it back
-    fn write_file(in_table: Table) -> Result<(), String>{
-        let get_file = OpenOptions::new()
-                                    .read(true)
-                                    .write(true)
-                                    .create(true)
-                                    .open("data/database.rdb");
+    /// Refuses a row with a reference that matches no row of the table it
+    /// refers to. NULL refers to nothing and is always accepted
+    fn check_references(storage: &mut Storage, table: &Table, row: &Row) -> Result<(), Error>{
+        for column in &table.columns{
+            let Some(fk) = &column.references else { continue };
+            let value = row.values.get(&column.name).unwrap_or(&Literal::Null);
+            if *value == Literal::Null {
+                continue;
+            }
 
-        let mut file = get_file.map_err(|err| err.to_string())?;
+            let parent = if fk.table == table.name {
+                Some(table.clone())
+            } else {
+                storage.table(&fk.table)?
+            };
+            let found = match parent {
+                Some(parent) => !VirtualMachine::find_rows(storage, &parent, &fk.column, value)?.is_empty(),
+                None => false,
+            };
 
-        let mut buff = Vec::new();
-        file.read_to_end(&mut buff).map_err(|err| err.to_string())?;
+            if !found {
+                return Err(Error::ConstraintViolation(
+                    format!("FOREIGN KEY constraint failed: {}.{} = {} has no matching row in {}({})",
+                            table.name, column.name, value, fk.table, fk.column)));
+            }
+        }
+        Ok(())
+    }
 
-        let mut memory_db: Database = match bincode::deserialize(&buff){
-            Ok(exists) => exists,
-            Err(_) => {
-                println!("{}", "No database found.. creating new DB instance".yellow());
-                Database{
-                    tables : BTreeMap::new(),
+    /// Every column, of any table in the same database, that refers to a table
+    pub(super) fn referencing(storage: &mut Storage, table_name: &str) -> Result<Vec<(Table, Column)>, Error>{
+        let mut children = Vec::new();
+        for table in storage.tables()?.tables.into_values(){
+            for column in &table.columns{
+                if matches!(&column.references, Some(fk) if fk.table == table_name) {
+                    children.push((table.clone(), column.clone()));
                 }
-            },
-        };
+            }
+        }
+        Ok(children)
+    }
 
-        memory_db.tables.insert(in_table.name.clone(), in_table);
+    /// Only a column whose values cannot repeat identifies the row a
+    /// reference points at
+    fn is_parent_key(table: &Table, column: &str) -> bool{
+        table.primary_key().name == column ||
+            table.indexes.iter().any(|index| index.unique && index.columns == [column])
+    }
 
-        let encode: Vec<u8> = bincode::serialize(&memory_db).unwrap();
-        let mut file = File::create("data/database.rdb").map_err(|err| err.to_string())?; 
+    /// Ids of the rows holding a value in a column, found through the
+    /// primary key or an index on the column when there is one
+    fn find_rows(storage: &mut Storage, table: &Table, column: &str, value: &Literal) -> Result<Vec<i64>, Error>{
+        if table.primary_key().name == column {
+            return match value {
+                Literal::Number(id) if storage.row(table, *id)?.is_some() => Ok(vec![*id]),
+                _ => Ok(Vec::new()),
+            };
+        }
 
-        file.write_all(&encode).map_err(|err| err.to_string())?;
-        
+        if let Some(index) = table.indexes.iter().find(|index| index.columns.first().map(String::as_str) == Some(column)) {
+            return storage.index_lookup(index, slice::from_ref(value));
+        }
+
+        let mut ids = Vec::new();
+        storage.scan(table, |id, row| {
+            if row.values.get(column) == Some(value) {
+                ids.push(id);
+            }
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    fn still_referenced(child: &Table, column: &Column, fk: &ForeignKey, key: &Literal) -> Error{
+        Error::ConstraintViolation(
+            format!("FOREIGN KEY constraint failed: {}.{} still refers to {}({}) = {}",
+                    child.name, column.name, fk.table, fk.column, key))
+    }
+
+    fn duplicate_key(table: &Table, id: i64) -> Error{
+        Error::ConstraintViolation(
+            format!("PRIMARY KEY constraint failed: {}.{} already has a row with key {}",
+                    table.name, table.primary_key().name, id))
+    }
+
+    /// Looks up the definition of a table, its rows stay on disk
+    fn read_file(&mut self, tablename: &str) -> Result<Table, Error> { 
+        match self.table(tablename)?{
+            Some(table) => Ok(table),
+            None => Err(Error::UnknownTable(tablename.to_string())),
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), Error>{
+        for storage in self.storages_mut(){
+            storage.commit()?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self){
+        self.storages_mut().for_each(Storage::rollback);
+    }
+
+    fn storages_mut(&mut self) -> impl Iterator<Item = &mut Storage>{
+        std::iter::once(&mut self.main).chain(self.attached.values_mut())
+    }
+
+    /// Splits `other.table` into the attached database it names and the table
+    fn split_name(tablename: &str) -> (Option<&str>, &str){
+        match tablename.split_once('.'){
+            Some((schema_name, table_name)) => (Some(schema_name), table_name),
+            None => (None, tablename),
+        }
+    }
+
+    fn storage_mut(&mut self, schema_name: Option<&str>) -> Result<&mut Storage, Error>{
+        match schema_name {
+            None | Some("main") => Ok(&mut self.main),
+            Some(name) => self.attached.get_mut(name)
+                .ok_or_else(|| Error::Execution(format!("no such database: {}", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Connection, Error, Literal};
+    use crate::vm::test_util::{names, query_plan};
+
+    /// Parents 1 and 2, each with a child pointing at it through a column
+    /// that is itself UNIQUE, so other rows may refer to the child in turn
+    fn families(child_column: &str) -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute(&format!("CREATE TABLE parent (id int, name varchar);
+                               CREATE TABLE child (id int, {} REFERENCES parent(id) ON DELETE SET NULL);
+                               INSERT INTO parent (name) VALUES ('first');
+                               INSERT INTO parent (name) VALUES ('second');
+                               INSERT INTO child (code) VALUES (1);
+                               INSERT INTO child (code) VALUES (2);", child_column))?;
+        Ok(conn)
+    }
+
+    fn codes(conn: &mut Connection) -> Result<Vec<Literal>, Error>{
+        Ok(conn.query("SELECT code FROM child;")?.into_iter().map(|row| row[0].clone()).collect())
+    }
+
+    #[test]
+    fn set_null_clears_only_the_children_of_the_deleted_row() -> Result<(), Error>{
+        let mut conn = families("code int UNIQUE")?;
+
+        assert_eq!(conn.execute("DELETE FROM parent WHERE id = 1;")?, 1);
+        assert_eq!(codes(&mut conn)?, [Literal::Null, Literal::Number(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_null_keeps_the_constraints_of_the_child() -> Result<(), Error>{
+        let mut conn = families("code int NOT NULL")?;
+
+        assert!(matches!(conn.execute("DELETE FROM parent WHERE id = 1;"), Err(Error::ConstraintViolation(_))));
+        assert_eq!(conn.query("SELECT * FROM parent;")?.len(), 2);
+        assert_eq!(codes(&mut conn)?, [Literal::Number(1), Literal::Number(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_null_refuses_to_orphan_rows_referring_to_the_child() -> Result<(), Error>{
+        let mut conn = families("code int UNIQUE")?;
+        conn.execute("CREATE TABLE grandchild (id int, code int REFERENCES child(code));
+                      INSERT INTO grandchild (code) VALUES (1);")?;
+
+        assert!(matches!(conn.execute("DELETE FROM parent WHERE id = 1;"), Err(Error::ConstraintViolation(_))));
+        assert_eq!(conn.query("SELECT * FROM parent;")?.len(), 2);
+        assert_eq!(codes(&mut conn)?, [Literal::Number(1), Literal::Number(2)]);
+
+        // a child nothing refers to is still cleared
+        conn.execute("DELETE FROM parent WHERE id = 2;")?;
+        assert_eq!(codes(&mut conn)?, [Literal::Number(1), Literal::Null]);
+        Ok(())
+    }
+
+    /// An order of a bolt, of which 10 are in stock
+    fn shop() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE orders (id int, item varchar NOT NULL);
+                      CREATE TABLE stock (item varchar, qty int CHECK (qty >= 0));
+                      INSERT INTO stock (item, qty) VALUES ('bolt', 10);")?;
+        Ok(conn)
+    }
+
+    fn stock(conn: &mut Connection) -> Result<Literal, Error>{
+        Ok(conn.query("SELECT qty FROM stock;")?.rows[0][0].clone())
+    }
+
+    #[test]
+    fn failed_statement_keeps_the_transaction_from_committing() -> Result<(), Error>{
+        let mut conn = shop()?;
+        conn.execute("BEGIN;
+                      INSERT INTO orders (item) VALUES ('bolt');")?;
+        assert!(conn.execute("UPDATE stock SET qty = -1 WHERE item = 'bolt';").is_err());
+
+        // nothing runs until the transaction is rolled back
+        assert!(conn.execute("COMMIT;").is_err());
+        assert!(conn.execute("INSERT INTO orders (item) VALUES ('nut');").is_err());
+        assert!(conn.query("SELECT * FROM orders;").is_err());
+
+        conn.execute("ROLLBACK;")?;
+        assert!(conn.query("SELECT * FROM orders;")?.is_empty());
+        assert_eq!(stock(&mut conn)?, Literal::Number(10));
+
+        // the next transaction starts afresh
+        conn.execute("BEGIN;
+                      INSERT INTO orders (item) VALUES ('bolt');
+                      UPDATE stock SET qty = 9 WHERE item = 'bolt';
+                      COMMIT;")?;
+        assert_eq!(conn.query("SELECT * FROM orders;")?.len(), 1);
+        assert_eq!(stock(&mut conn)?, Literal::Number(9));
+        Ok(())
+    }
+
+    #[test]
+    fn rolling_back_to_a_savepoint_recovers_from_a_failure() -> Result<(), Error>{
+        let mut conn = shop()?;
+        conn.execute("BEGIN;
+                      INSERT INTO orders (item) VALUES ('bolt');
+                      SAVEPOINT line;")?;
+        assert!(conn.execute("INSERT INTO orders (id) VALUES (2);").is_err());
+        assert!(conn.execute("RELEASE line;").is_err());
+
+        conn.execute("ROLLBACK TO line;
+                      UPDATE stock SET qty = 9 WHERE item = 'bolt';
+                      COMMIT;")?;
+        assert_eq!(conn.query("SELECT * FROM orders;")?.len(), 1);
+        assert_eq!(stock(&mut conn)?, Literal::Number(9));
+        Ok(())
+    }
+
+    #[test]
+    fn failure_outside_a_transaction_only_undoes_itself() -> Result<(), Error>{
+        let mut conn = shop()?;
+        assert!(conn.execute("UPDATE stock SET qty = -1 WHERE item = 'bolt';").is_err());
+        conn.execute("UPDATE stock SET qty = 8 WHERE item = 'bolt';")?;
+        assert_eq!(stock(&mut conn)?, Literal::Number(8));
+        Ok(())
+    }
+
+    /// Plants of which only some have a known height
+    fn garden() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE plants (id int, name varchar, height int);
+                      INSERT INTO plants (name, height) VALUES ('fern', 30);
+                      INSERT INTO plants (name) VALUES ('moss');
+                      INSERT INTO plants (name, height) VALUES ('oak', NULL);
+                      INSERT INTO plants (name, height) VALUES ('reed', 120);")?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn missing_values_are_null_and_found_with_is_null() -> Result<(), Error>{
+        let mut conn = garden()?;
+
+        assert_eq!(names(&mut conn, "SELECT name FROM plants WHERE height IS NULL;")?, ["moss", "oak"]);
+        assert_eq!(names(&mut conn, "SELECT name FROM plants WHERE height IS NOT NULL;")?, ["fern", "reed"]);
+        assert_eq!(conn.query("SELECT height FROM plants WHERE name = 'moss';")?.rows, [[Literal::Null]]);
+        Ok(())
+    }
+
+    #[test]
+    fn comparisons_with_null_match_no_row() -> Result<(), Error>{
+        let mut conn = garden()?;
+
+        assert!(names(&mut conn, "SELECT name FROM plants WHERE height = NULL;")?.is_empty());
+        assert!(names(&mut conn, "SELECT name FROM plants WHERE height != NULL;")?.is_empty());
+        // unknown stays unknown when negated
+        assert_eq!(names(&mut conn, "SELECT name FROM plants WHERE NOT height > 50;")?, ["fern"]);
+        // but is settled by the other side of OR and AND
+        assert_eq!(names(&mut conn, "SELECT name FROM plants WHERE height > 50 OR name = 'moss';")?, ["moss", "reed"]);
+        assert!(names(&mut conn, "SELECT name FROM plants WHERE height > 50 AND name = 'oak';")?.is_empty());
+        Ok(())
+    }
+
+    /// Accounts that need an owner, start out open and never go negative
+    fn accounts() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE accounts (id int, owner varchar NOT NULL, status varchar DEFAULT 'open',
+                                             balance int CHECK (balance >= 0));
+                      INSERT INTO accounts (owner, balance) VALUES ('ann', 10);")?;
+        Ok(conn)
+    }
+
+    fn violation(result: Result<usize, Error>, constraint: &str) -> bool{
+        matches!(result, Err(Error::ConstraintViolation(message)) if message.starts_with(constraint))
+    }
+
+    #[test]
+    fn not_null_refuses_a_missing_value() -> Result<(), Error>{
+        let mut conn = accounts()?;
+
+        assert!(violation(conn.execute("INSERT INTO accounts (balance) VALUES (5);"), "NOT NULL"));
+        assert!(violation(conn.execute("INSERT INTO accounts (owner) VALUES (NULL);"), "NOT NULL"));
+        assert!(violation(conn.execute("UPDATE accounts SET owner = NULL WHERE id = 1;"), "NOT NULL"));
+        assert_eq!(names(&mut conn, "SELECT owner FROM accounts;")?, ["ann"]);
+        Ok(())
+    }
+
+    #[test]
+    fn default_fills_in_a_column_left_out() -> Result<(), Error>{
+        let mut conn = accounts()?;
+        conn.execute("INSERT INTO accounts (owner, status) VALUES ('bob', 'closed');
+                      INSERT INTO accounts (owner, status) VALUES ('cal', NULL);")?;
+
+        // only a column left out gets the default, not one given as NULL
+        assert_eq!(conn.query("SELECT status FROM accounts;")?.rows,
+                   [[Literal::String(String::from("open"))], [Literal::String(String::from("closed"))], [Literal::Null]]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_refuses_false_but_lets_null_through() -> Result<(), Error>{
+        let mut conn = accounts()?;
+
+        assert!(violation(conn.execute("INSERT INTO accounts (owner, balance) VALUES ('bob', -1);"), "CHECK"));
+        assert!(violation(conn.execute("UPDATE accounts SET balance = -1 WHERE id = 1;"), "CHECK"));
+        assert_eq!(conn.query("SELECT balance FROM accounts;")?.rows, [[Literal::Number(10)]]);
+
+        conn.execute("INSERT INTO accounts (owner, balance) VALUES ('cal', NULL);")?;
+        assert_eq!(names(&mut conn, "SELECT owner FROM accounts WHERE balance IS NULL;")?, ["cal"]);
+        Ok(())
+    }
+
+    #[test]
+    fn primary_key_refuses_a_second_row_with_the_same_key() -> Result<(), Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE users (uid int PRIMARY KEY, name varchar);
+                      INSERT INTO users (uid, name) VALUES (1, 'ann');
+                      INSERT INTO users (uid, name) VALUES (2, 'bob');")?;
+
+        assert!(violation(conn.execute("INSERT INTO users (uid, name) VALUES (1, 'cal');"), "PRIMARY KEY"));
+        assert!(violation(conn.execute("UPDATE users SET uid = 1 WHERE uid = 2;"), "PRIMARY KEY"));
+        assert_eq!(names(&mut conn, "SELECT name FROM users;")?, ["ann", "bob"]);
+
+        // a key nothing else holds moves the row
+        conn.execute("UPDATE users SET uid = 3 WHERE uid = 2;")?;
+        assert_eq!(names(&mut conn, "SELECT name FROM users WHERE uid = 3;")?, ["bob"]);
+        Ok(())
+    }
+
+    #[test]
+    fn autoincrement_never_hands_out_a_deleted_key() -> Result<(), Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE tickets (id int PRIMARY KEY AUTOINCREMENT, name varchar);
+                      CREATE TABLE seats (id int PRIMARY KEY, name varchar);")?;
+        for table in ["tickets", "seats"] {
+            conn.execute(&format!("INSERT INTO {0} (name) VALUES ('first');
+                                   INSERT INTO {0} (name) VALUES ('second');
+                                   DELETE FROM {0} WHERE id = 2;
+                                   INSERT INTO {0} (name) VALUES ('third');", table))?;
+        }
+
+        // without AUTOINCREMENT the largest key left is counted from
+        assert_eq!(conn.query("SELECT id FROM tickets;")?.rows, [[Literal::Number(1)], [Literal::Number(3)]]);
+        assert_eq!(conn.query("SELECT id FROM seats;")?.rows, [[Literal::Number(1)], [Literal::Number(2)]]);
+        Ok(())
+    }
+
+    /// Members with a unique email, and a unique seat in each room
+    fn members() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE members (id int, email varchar UNIQUE, room int, seat int, UNIQUE (room, seat));
+                      INSERT INTO members (email, room, seat) VALUES ('ann@x', 1, 1);
+                      INSERT INTO members (email, room, seat) VALUES ('bob@x', 1, 2);")?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn unique_refuses_a_taken_value_on_insert() -> Result<(), Error>{
+        let mut conn = members()?;
+
+        assert!(violation(conn.execute("INSERT INTO members (email) VALUES ('ann@x');"), "UNIQUE"));
+        assert!(violation(conn.execute("INSERT INTO members (room, seat) VALUES (1, 2);"), "UNIQUE"));
+        assert_eq!(conn.query("SELECT * FROM members;")?.len(), 2);
+
+        // only the whole of a constraint over several columns has to differ
+        conn.execute("INSERT INTO members (email, room, seat) VALUES ('cal@x', 2, 2);")?;
+        // and NULLs never clash
+        conn.execute("INSERT INTO members (email) VALUES (NULL);
+                      INSERT INTO members (email) VALUES (NULL);")?;
+        assert_eq!(conn.query("SELECT * FROM members;")?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn unique_refuses_a_taken_value_on_update() -> Result<(), Error>{
+        let mut conn = members()?;
+
+        assert!(violation(conn.execute("UPDATE members SET email = 'ann@x' WHERE id = 2;"), "UNIQUE"));
+        assert!(violation(conn.execute("UPDATE members SET seat = 1 WHERE id = 2;"), "UNIQUE"));
+        assert_eq!(names(&mut conn, "SELECT email FROM members WHERE room = 1 AND seat = 2;")?, ["bob@x"]);
+
+        // a row may keep its own value, and the value it gives up is free again
+        conn.execute("UPDATE members SET email = 'bob@x' WHERE id = 2;
+                      UPDATE members SET email = 'ann@y' WHERE id = 1;
+                      INSERT INTO members (email) VALUES ('ann@x');")?;
+        assert_eq!(names(&mut conn, "SELECT email FROM members;")?, ["ann@y", "bob@x", "ann@x"]);
+        Ok(())
+    }
+
+    /// Parts with an index on their colour
+    fn parts() -> Result<Connection, Error>{
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE parts (id int, name varchar, colour varchar);
+                      CREATE INDEX bycolour ON parts (colour);
+                      INSERT INTO parts (name, colour) VALUES ('gear', 'red');
+                      INSERT INTO parts (name, colour) VALUES ('bolt', 'blue');
+                      INSERT INTO parts (name, colour) VALUES ('nut', 'red');")?;
+        Ok(conn)
+    }
+
+    /// Names of the parts of a colour, along with whether bycolour found them
+    fn coloured(conn: &mut Connection, colour: &str) -> Result<(Vec<String>, bool), Error>{
+        let sql = format!("SELECT name FROM parts WHERE colour = '{}';", colour);
+        let indexed = query_plan(conn, &sql)?.iter().any(|(_, _, detail)| detail.contains("USING INDEX bycolour"));
+        let mut found = names(conn, &sql)?;
+        found.sort();
+        Ok((found, indexed))
+    }
+
+    #[test]
+    fn index_follows_inserts_updates_and_deletes() -> Result<(), Error>{
+        let mut conn = parts()?;
+        assert_eq!(coloured(&mut conn, "red")?, (vec![String::from("gear"), String::from("nut")], true));
+
+        conn.execute("UPDATE parts SET colour = 'blue' WHERE id = 1;")?;
+        assert_eq!(coloured(&mut conn, "red")?, (vec![String::from("nut")], true));
+        assert_eq!(coloured(&mut conn, "blue")?, (vec![String::from("bolt"), String::from("gear")], true));
+
+        conn.execute("DELETE FROM parts WHERE id = 3;
+                      INSERT INTO parts (name, colour) VALUES ('washer', 'red');")?;
+        assert_eq!(coloured(&mut conn, "red")?, (vec![String::from("washer")], true));
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_index_is_no_longer_used_or_kept() -> Result<(), Error>{
+        let mut conn = parts()?;
+        conn.execute("DROP INDEX bycolour;")?;
+        assert!(conn.execute("DROP INDEX bycolour;").is_err());
+
+        // rows written while there is no index are picked up when it is made again
+        conn.execute("UPDATE parts SET colour = 'blue' WHERE id = 1;")?;
+        assert_eq!(coloured(&mut conn, "red")?, (vec![String::from("nut")], false));
+
+        conn.execute("CREATE INDEX bycolour ON parts (colour);")?;
+        assert_eq!(coloured(&mut conn, "red")?, (vec![String::from("nut")], true));
+        assert_eq!(coloured(&mut conn, "blue")?, (vec![String::from("bolt"), String::from("gear")], true));
         Ok(())
     }
 }